@@ -1,5 +1,7 @@
 use clap::builder::styling::{AnsiColor, Effects, Styles};
+use clap::builder::PossibleValuesParser;
 use clap::{Parser, Subcommand};
+use crate::kit::KITS;
 
 
 fn styles() -> Styles {
@@ -32,12 +34,51 @@ pub struct Args {
     /// filter read by min_length
     #[arg(short, long, default_value = "100")]
     pub min_length: usize,
+    /// filter read by max_length, 0 means no limit
+    #[arg(long, default_value = "0")]
+    pub max_length: usize,
+    /// filter read by mean quality (computed from error probabilities), 0 means no limit
+    #[arg(long, default_value = "0")]
+    pub min_qual: f64,
+    /// filter read by max fraction of N bases
+    #[arg(long, default_value = "1.0")]
+    pub max_n: f64,
+    /// filter read by max homopolymer run length, 0 means no limit
+    #[arg(long, default_value = "0")]
+    pub max_homopolymer: usize,
+    /// filter read by min insert length inside the first level patterns, 0 means no limit
+    #[arg(long, default_value = "0")]
+    pub min_insert: usize,
+    /// filter read by max insert length inside the first level patterns, 0 means no limit
+    #[arg(long, default_value = "0")]
+    pub max_insert: usize,
+    /// tolerance of expected_len in pattern files, insert range is expected_len x (1 ± tolerance)
+    #[arg(long, default_value = "0.1")]
+    pub len_tolerance: f32,
+    /// reads with an expected_len pattern pair and insert shorter than dimer_len are classified as dimer
+    #[arg(long, default_value = "50")]
+    pub dimer_len: usize,
     /// pattern_files for split
-	#[arg(short,long, required = true, num_args = 1..,value_delimiter = ' ', required = true)]
+	#[arg(short,long, required_unless_present = "kits", num_args = 1..,value_delimiter = ' ')]
 	pub pattern_files: Option<Vec<String>>,
-    /// pattern_db_file for split
-	#[arg(short = 'd', long = "db", required = true)]
-    pub pattern_db_file: Option<String>,
+    /// pattern_db_files for split, tsv or fasta, may be gzipped
+	#[arg(short = 'd', long = "db", required_unless_present = "kits", num_args = 1.., value_delimiter = ' ')]
+    pub pattern_db_files: Option<Vec<String>>,
+    /// built-in barcode kits, together they add one level after the pattern files, see `jasper kits`
+    #[arg(long = "kit", num_args = 1.., value_delimiter = ' ', value_parser = PossibleValuesParser::new(KITS.iter().map(|kit| kit.name)))]
+    pub kits: Vec<String>,
+    /// skip and count malformed fastq records instead of stopping the run.
+    #[arg(long)]
+    pub skip_bad_reads: bool,
+    /// start even when two patterns of a level lie within the allowed error of each other.
+    #[arg(long)]
+    pub skip_validate: bool,
+    /// read the passphrase of .safe files from the first line of this file instead of JASPER_PASSPHRASE or the prompt
+    #[arg(long)]
+    pub passphrase_file: Option<String>,
+    /// age identity file to decrypt .safe files encrypted to recipients
+    #[arg(long = "identity")]
+    pub identity_files: Vec<String>,
     /// fusion file to detect fusion
     #[arg(short = 'f', long = "fusion", default_value= "")]
     pub fusion_file: String,
@@ -50,12 +91,36 @@ pub struct Args {
     /// windows size to finder pattern <left,right>
     #[arg(short,long,value_delimiter = ',', default_value="400,400")]
     pub window_size: Vec<usize>,
+    /// set a window size for each pattern file use whiteblack, left and right use comma, default is window_size.
+    #[arg(long = "level-window", num_args = 1..,value_delimiter = ' ',value_parser=window_validator)]
+    pub level_window: Vec<(usize,usize)>,
+    /// retry with doubled windows up to the whole read when no pattern is found in the window.
+    #[arg(long = "adaptive")]
+    pub window_adaptive: bool,
     /// set a errate for multiple pattern use whiteblack,set left and right errate use comma, errate range in <0-0.5>, err = pattern_len x errate.
     #[arg(short = 'e', long, num_args = 1..,value_delimiter = ' ', default_value="0.2,0.2",value_parser=errrate_validator)]
     pub pattern_errate: Vec<(f32,f32)>,
     /// whether to trim seq, 0 means trim all, 1 means keep one pattern, 2 means keep two pattern...
     #[arg(long, default_value = "0")]
     pub trim_n: usize,
+    /// quality trim the read ends after pattern trimming, <window> sliding window or <mott> modified Mott
+    #[arg(long, default_value = "none", value_parser = ["none","window","mott"])]
+    pub qtrim: String,
+    /// quality threshold for qtrim
+    #[arg(long, default_value = "10")]
+    pub qtrim_q: u8,
+    /// window size for qtrim window
+    #[arg(long, default_value = "10")]
+    pub qtrim_window: usize,
+    /// detect polyA before the 3' pattern or polyT after the 5' pattern, trim it and infer strand from it.
+    #[arg(long)]
+    pub polya: bool,
+    /// min length of polyA/polyT tail
+    #[arg(long, default_value = "15")]
+    pub polya_min: usize,
+    /// window size next to the first level patterns to search polyA/polyT
+    #[arg(long, default_value = "100")]
+    pub polya_window: usize,
     /// write_type for split
     #[arg(long, default_value = "type", value_parser = ["names","type"])]
     pub write_type: String,
@@ -71,9 +136,48 @@ pub struct Args {
     /// set a maxdist for patterns, set left and right maxdist use comma.
     #[arg(long = "maxdist", num_args = 1..,value_delimiter = ',', default_value="4")]
     pub pattern_maxdist: Vec<usize>,
+    /// match patterns cut by the read start or end on their present part, error = overlap_len x errate.
+    #[arg(long = "end-match")]
+    pub end_match: bool,
+    /// min overlap between pattern and read boundary for end-match.
+    #[arg(long = "min-overlap", default_value = "10")]
+    pub min_overlap: usize,
+    /// retry unknown reads with rescue_errate, whole read windows and single match on every level.
+    #[arg(long)]
+    pub rescue: bool,
+    /// errate of the rescue pass, range in <0-0.5>
+    #[arg(long, default_value = "0.3", value_parser = rescue_errate_validator)]
+    pub rescue_errate: f32,
+    /// report over-represented k-mers and pattern db entries in the read-end windows of unknown reads.
+    #[arg(long)]
+    pub discover: bool,
+    /// max unknown reads collected for the discover report
+    #[arg(long, default_value = "10000")]
+    pub discover_reads: usize,
     /// set record id sep delimiter.
     #[arg(long = "id_sep", default_value="%")]
     pub id_sep: String,
+    /// write every read trimmed to stdout as a single stream <fastq or ubam>, assignment is kept in SAM-style tags (BC/XR/XS/XN/XT).
+    #[arg(long, num_args = 0..=1, default_missing_value = "fastq", value_parser = ["fastq","ubam"])]
+    pub stdout: Option<String>,
+    /// keep the original read id untouched instead of joining the assignment with id_sep.
+    #[arg(long = "keep-id")]
+    pub keep_id: bool,
+    /// append the assignment to the original header comment as <kv> key=value pairs or <sam> SAM-style tags.
+    #[arg(long = "tag-format", value_parser = ["none","kv","sam"])]
+    pub tag_format: Option<String>,
+    /// write the trim coordinates on the original read into the header (trim=L-R or XC:Z:L-R).
+    #[arg(long = "trim-coord")]
+    pub trim_coord: bool,
+    /// write the trimmed-off left and right sequences and qualities into the header (B1/Y1 and B2/Y2 tags).
+    #[arg(long = "trim-seqs")]
+    pub trim_seqs: bool,
+    /// soft-mask matched patterns of every level in lowercase and keep the whole read instead of trimming.
+    #[arg(long)]
+    pub softmask: bool,
+    /// reverse complement rs reads at output, so all reads are written in forward strand orientation.
+    #[arg(long)]
+    pub orient: bool,
 
 
 
@@ -86,24 +190,122 @@ pub enum Commands {
     Encrypt {
         /// The database file to encrypt
         file: String,
+        /// age X25519 recipient (age1...) to encrypt for, a passphrase is used when no recipient is given
+        #[arg(short = 'r', long = "recipient")]
+        recipients: Vec<String>,
+        /// file of age X25519 recipients, one per line
+        #[arg(short = 'R', long = "recipients-file")]
+        recipients_files: Vec<String>,
+        /// read the passphrase from the first line of this file instead of JASPER_PASSPHRASE or the prompt
+        #[arg(long)]
+        passphrase_file: Option<String>,
+    },
+    /// Check pairwise edit distances of the patterns within each level, takes the same options as a run
+    Validate {
+        /// run options, e.g. -p primer.list index.list -d pattern.db -e 0.2,0.2
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true, num_args = 0..)]
+        args: Vec<String>,
+    },
+    /// Decrypt a .safe file, the key is required
+    Decrypt {
+        /// The .safe file to decrypt
+        file: String,
+        /// output file, default is the file name without .safe
+        #[arg(short, long)]
+        output: Option<String>,
+        #[command(flatten)]
+        key: KeyArgs,
+    },
+    /// List the built-in barcode kits of --kit
+    Kits,
+    /// Inspect, stamp or re-key a pattern database
+    Db {
+        #[command(subcommand)]
+        command: DbCommands,
     },
 }
 
+#[derive(Subcommand, Debug, Clone)]
+pub enum DbCommands {
+    /// List entry names and sequence lengths of a database without revealing the sequences
+    Info {
+        /// The database file, plain or .safe
+        file: String,
+        #[command(flatten)]
+        key: KeyArgs,
+    },
+    /// Write the ##kit, ##version, ##date and ##checksum header into a plain database
+    Stamp {
+        /// The plain database file
+        file: String,
+        /// kit name
+        #[arg(long)]
+        kit: Option<String>,
+        /// kit version
+        #[arg(long = "kit-version")]
+        version: Option<String>,
+        /// kit date
+        #[arg(long)]
+        date: Option<String>,
+    },
+    /// Re-encrypt a .safe file in place with a new passphrase or new recipients
+    Rekey {
+        /// The .safe file to re-key
+        file: String,
+        #[command(flatten)]
+        key: KeyArgs,
+        /// new age X25519 recipient (age1...), a new passphrase is used when no recipient is given
+        #[arg(short = 'r', long = "recipient")]
+        recipients: Vec<String>,
+        /// file of new age X25519 recipients, one per line
+        #[arg(short = 'R', long = "recipients-file")]
+        recipients_files: Vec<String>,
+        /// read the new passphrase from the first line of this file instead of JASPER_NEW_PASSPHRASE or the prompt
+        #[arg(long)]
+        new_passphrase_file: Option<String>,
+    },
+}
+
+/// Key of a .safe file for the subcommands
+#[derive(clap::Args, Debug, Clone)]
+pub struct KeyArgs {
+    /// read the passphrase from the first line of this file instead of JASPER_PASSPHRASE or the prompt
+    #[arg(long)]
+    pub passphrase_file: Option<String>,
+    /// age identity file to decrypt files encrypted to recipients
+    #[arg(long = "identity")]
+    pub identity_files: Vec<String>,
+}
+
 fn errrate_validator(input: &str) -> Result<(f32,f32), String> {
     let pattern_errate: Vec<&str> =  input.split(',').collect();
     if pattern_errate.len() != 2 {
         return Err("pattern_errate should be two comma-separated values".to_string());
     }
-    let errate1 = pattern_errate[0].parse();
-    let errate2 = pattern_errate[1].parse();
-    match (errate1, errate2) {
-        (Ok(errate1), Ok(errate2)) if errate1 >= 0.0 && errate1 <= 0.5 && errate2 >= 0.0 && errate2 <= 0.5 => {
-            Ok((errate1, errate2))
-        },
+    match (errate_range(pattern_errate[0]), errate_range(pattern_errate[1])) {
+        (Some(errate1), Some(errate2)) => Ok((errate1, errate2)),
         _ => Err("Error pattern_errate. They should be floats in the range 0 to 0.5.".to_string()),
     }
 }
 
+fn rescue_errate_validator(input: &str) -> Result<f32, String> {
+    errate_range(input).ok_or_else(|| "Error rescue_errate. It should be a float in the range 0 to 0.5.".to_string())
+}
+
+fn errate_range(input: &str) -> Option<f32> {
+    input.trim().parse().ok().filter(|errate| (0.0..=0.5).contains(errate))
+}
+
+fn window_validator(input: &str) -> Result<(usize,usize), String> {
+    match input.split_once(',') {
+        Some((left, right)) => match (left.parse(), right.parse()) {
+            (Ok(left), Ok(right)) => Ok((left, right)),
+            _ => Err("Error window size. They should be two comma-separated integers.".to_string()),
+        },
+        None => Err("window size should be two comma-separated values".to_string()),
+    }
+}
+
 // pub fn get_input_args(){
 // 	let args = Args::parse();
 // 	println!("{:?}", args);
@@ -0,0 +1,174 @@
+use flate2::write::DeflateEncoder;
+use flate2::{Compression, Crc};
+use log::warn;
+use std::io::{Result, Write};
+
+// longest read name a BAM record can hold, l_read_name is one byte including the NUL
+const MAX_READ_NAME: usize = 254;
+// max uncompressed size of one BGZF block
+const BGZF_BLOCK_SIZE: usize = 0xff00;
+// empty BGZF block marks the end of a BAM file
+const BGZF_EOF: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43, 0x02, 0x00,
+    0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// Minimal unaligned BAM (uBAM) writer, every record is written unmapped with Z-type tags.
+pub struct BamWriter<W: Write> {
+    inner: W,
+    buffer: Vec<u8>,
+    name_truncated: bool,
+}
+
+impl<W: Write> BamWriter<W> {
+    pub fn new(inner: W) -> Result<BamWriter<W>> {
+        let mut writer = BamWriter {
+            inner,
+            buffer: Vec::with_capacity(BGZF_BLOCK_SIZE),
+            name_truncated: false,
+        };
+        let header = format!(
+            "@HD\tVN:1.6\tSO:unknown\n@PG\tID:jasper\tPN:jasper\tVN:{}\n",
+            env!("CARGO_PKG_VERSION")
+        );
+        writer.push(b"BAM\x01")?;
+        writer.push(&(header.len() as i32).to_le_bytes())?;
+        writer.push(header.as_bytes())?;
+        // no reference sequences
+        writer.push(&0i32.to_le_bytes())?;
+        Ok(writer)
+    }
+
+    pub fn write_record(&mut self, name: &str, seq: &[u8], qual: &[u8], tags: &[(&str, String)]) -> Result<()> {
+        let name = if name.len() > MAX_READ_NAME {
+            if !self.name_truncated {
+                warn!("read names longer than {} bytes are truncated in the BAM output, e.g. {}", MAX_READ_NAME, name);
+                self.name_truncated = true;
+            }
+            &name[..name.floor_char_boundary(MAX_READ_NAME)]
+        } else {
+            name
+        };
+        let mut record: Vec<u8> = Vec::with_capacity(64 + name.len() + seq.len() * 2);
+        record.extend_from_slice(&(-1i32).to_le_bytes()); // refID
+        record.extend_from_slice(&(-1i32).to_le_bytes()); // pos
+        record.push((name.len() + 1) as u8); // l_read_name
+        record.push(255); // mapq
+        record.extend_from_slice(&4680u16.to_le_bytes()); // bin of unmapped read
+        record.extend_from_slice(&0u16.to_le_bytes()); // n_cigar_op
+        record.extend_from_slice(&4u16.to_le_bytes()); // flag: unmapped
+        record.extend_from_slice(&(seq.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(-1i32).to_le_bytes()); // next refID
+        record.extend_from_slice(&(-1i32).to_le_bytes()); // next pos
+        record.extend_from_slice(&0i32.to_le_bytes()); // tlen
+        record.extend_from_slice(name.as_bytes());
+        record.push(0);
+        for pair in seq.chunks(2) {
+            let high = encode_base(pair[0]) << 4;
+            let low = pair.get(1).map(|b| encode_base(*b)).unwrap_or(0);
+            record.push(high | low);
+        }
+        record.extend(qual.iter().map(|q| q.saturating_sub(33)));
+        for (tag, value) in tags {
+            record.extend_from_slice(tag.as_bytes());
+            record.push(b'Z');
+            record.extend_from_slice(value.as_bytes());
+            record.push(0);
+        }
+        self.push(&(record.len() as u32).to_le_bytes())?;
+        self.push(&record)
+    }
+
+    pub fn finish(&mut self) -> Result<()> {
+        self.flush_block()?;
+        self.inner.write_all(&BGZF_EOF)?;
+        self.inner.flush()
+    }
+
+    fn push(&mut self, mut data: &[u8]) -> Result<()> {
+        while !data.is_empty() {
+            let n = (BGZF_BLOCK_SIZE - self.buffer.len()).min(data.len());
+            self.buffer.extend_from_slice(&data[..n]);
+            data = &data[n..];
+            if self.buffer.len() == BGZF_BLOCK_SIZE {
+                self.flush_block()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush_block(&mut self) -> Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&self.buffer)?;
+        let compressed = encoder.finish()?;
+        let mut crc = Crc::new();
+        crc.update(&self.buffer);
+        // gzip header with the BC extra subfield, BSIZE is the total block size minus 1
+        let block_size = 18 + compressed.len() + 8;
+        self.inner.write_all(&[0x1f, 0x8b, 0x08, 0x04, 0, 0, 0, 0, 0, 0xff, 6, 0, b'B', b'C', 2, 0])?;
+        self.inner.write_all(&((block_size - 1) as u16).to_le_bytes())?;
+        self.inner.write_all(&compressed)?;
+        self.inner.write_all(&crc.sum().to_le_bytes())?;
+        self.inner.write_all(&(self.buffer.len() as u32).to_le_bytes())?;
+        self.buffer.clear();
+        Ok(())
+    }
+}
+
+fn encode_base(base: u8) -> u8 {
+    match base.to_ascii_uppercase() {
+        b'=' => 0,
+        b'A' => 1,
+        b'C' => 2,
+        b'M' => 3,
+        b'G' => 4,
+        b'R' => 5,
+        b'S' => 6,
+        b'V' => 7,
+        b'T' => 8,
+        b'W' => 9,
+        b'Y' => 10,
+        b'H' => 11,
+        b'K' => 12,
+        b'D' => 13,
+        b'B' => 14,
+        _ => 15,
+    }
+}
+
+#[test]
+pub fn test_bam_roundtrip(){
+    use flate2::read::MultiGzDecoder;
+    use std::io::Read;
+    let mut output = Vec::new();
+    let mut writer = BamWriter::new(&mut output).unwrap();
+    let long_name = "r".repeat(300);
+    writer.write_record("read1", b"ACGTN", b"I#5+?", &[("CB", "CB01".to_string())]).unwrap();
+    writer.write_record(&long_name, b"A", b"I", &[]).unwrap();
+    writer.finish().unwrap();
+    assert!(output.ends_with(&BGZF_EOF));
+    let mut data = Vec::new();
+    MultiGzDecoder::new(output.as_slice()).read_to_end(&mut data).unwrap();
+    let int = |at: usize| i32::from_le_bytes(data[at..at + 4].try_into().unwrap());
+    assert_eq!(&data[..4], b"BAM\x01");
+    let text_len = int(4) as usize;
+    assert_eq!(int(8 + text_len), 0); // n_ref
+    let record = 12 + text_len;
+    let block_size = int(record) as usize;
+    assert_eq!((int(record + 4), int(record + 8)), (-1, -1));
+    let name_len = data[record + 12] as usize;
+    assert_eq!(u16::from_le_bytes([data[record + 18], data[record + 19]]), 4); // flag
+    assert_eq!(int(record + 20), 5); // l_seq
+    let name_at = record + 36;
+    assert_eq!(&data[name_at..name_at + name_len], b"read1\0");
+    let seq_at = name_at + name_len;
+    assert_eq!(&data[seq_at..seq_at + 3], &[0x12, 0x48, 0xf0]);
+    assert_eq!(&data[seq_at + 3..seq_at + 8], &[40, 2, 20, 10, 30]);
+    assert_eq!(&data[seq_at + 8..record + 4 + block_size], b"CBZCB01\0");
+    // the long name is cut to fit l_read_name
+    let next = record + 4 + block_size;
+    assert_eq!(data[next + 12] as usize, MAX_READ_NAME + 1);
+}
@@ -1,22 +1,146 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs::File;
 use std::path::Path;
 use log::info;
 use crate::fastq::ReadInfo;
+use crate::pattern::DbMeta;
+use crate::utils::mean_quality;
 use std::io::Write;
 
+// length histogram bin size in length_hist.tsv
+const LENGTH_BIN: usize = 100;
+
+// columns of QcStats in total_info.tsv and sample_qc.tsv
+const QC_COLUMNS: [&str; 9] = ["reads", "bases", "mean_length", "n50", "gc_content", "mean_q", "median_q", "q10_rate", "q20_rate"];
+
+#[derive(Debug, Default)]
+pub struct QcStats {
+    pub reads: u64,
+    pub bases: u64,
+    gc_bases: u64,
+    q10_bases: u64,
+    q20_bases: u64,
+    pub qtrim_bases: (u64, u64), // quality trimmed bases on left and right
+    read_qual_sum: f64,
+    read_qual_hist: BTreeMap<u32, u64>, // read mean quality x10 -> count
+    length_hist: BTreeMap<usize, u64>,  // read length -> count
+}
+impl QcStats {
+    pub fn add(&mut self, seq: &[u8], qual: &[u8]) {
+        self.reads += 1;
+        self.bases += seq.len() as u64;
+        self.gc_bases += seq.iter().filter(|b| matches!(b, b'G' | b'C' | b'g' | b'c')).count() as u64;
+        self.q10_bases += qual.iter().filter(|q| **q >= 33 + 10).count() as u64;
+        self.q20_bases += qual.iter().filter(|q| **q >= 33 + 20).count() as u64;
+        let read_qual = mean_quality(qual);
+        self.read_qual_sum += read_qual;
+        *self.read_qual_hist.entry((read_qual * 10.0).round() as u32).or_insert(0) += 1;
+        *self.length_hist.entry(seq.len()).or_insert(0) += 1;
+    }
+    pub fn add_qtrim(&mut self, qtrim_bases: (usize, usize)) {
+        self.qtrim_bases.0 += qtrim_bases.0 as u64;
+        self.qtrim_bases.1 += qtrim_bases.1 as u64;
+    }
+    fn rate(&self, count: u64) -> f64 {
+        if self.bases > 0 {
+            count as f64 / self.bases as f64
+        } else {
+            0.0
+        }
+    }
+    pub fn gc_content(&self) -> f64 {
+        self.rate(self.gc_bases)
+    }
+    pub fn q10_rate(&self) -> f64 {
+        self.rate(self.q10_bases)
+    }
+    pub fn q20_rate(&self) -> f64 {
+        self.rate(self.q20_bases)
+    }
+    pub fn mean_length(&self) -> f64 {
+        if self.reads > 0 {
+            self.bases as f64 / self.reads as f64
+        } else {
+            0.0
+        }
+    }
+    pub fn mean_qual(&self) -> f64 {
+        if self.reads > 0 {
+            self.read_qual_sum / self.reads as f64
+        } else {
+            0.0
+        }
+    }
+    pub fn median_qual(&self) -> f64 {
+        // median of read mean qualities
+        let mut seen = 0;
+        for (qual, count) in &self.read_qual_hist {
+            seen += count;
+            if seen * 2 >= self.reads {
+                return *qual as f64 / 10.0;
+            }
+        }
+        0.0
+    }
+    pub fn n50(&self) -> usize {
+        let mut seen = 0;
+        for (len, count) in self.length_hist.iter().rev() {
+            seen += *len as u64 * count;
+            if seen * 2 >= self.bases {
+                return *len;
+            }
+        }
+        0
+    }
+    pub fn binned_length_hist(&self) -> BTreeMap<usize, u64> {
+        let mut hist = BTreeMap::new();
+        for (len, count) in &self.length_hist {
+            *hist.entry(len / LENGTH_BIN * LENGTH_BIN).or_insert(0) += count;
+        }
+        hist
+    }
+    pub fn header(prefix: &str) -> String {
+        QcStats::default().columns(prefix, &[]).0
+    }
+    pub fn to_tsv(&self) -> String {
+        self.columns("", &[]).1
+    }
+    fn values(&self) -> [String; 9] {
+        [
+            self.reads.to_string(),
+            self.bases.to_string(),
+            format!("{:.1}", self.mean_length()),
+            self.n50().to_string(),
+            format!("{:.4}", self.gc_content()),
+            format!("{:.2}", self.mean_qual()),
+            format!("{:.1}", self.median_qual()),
+            format!("{:.4}", self.q10_rate()),
+            format!("{:.4}", self.q20_rate()),
+        ]
+    }
+    fn columns(&self, prefix: &str, skip: &[&str]) -> (String, String) {
+        // prefixed header and values, without the skipped columns
+        let (names, values): (Vec<String>, Vec<String>) = QC_COLUMNS
+            .iter()
+            .zip(self.values())
+            .filter(|(name, _)| !skip.contains(name))
+            .map(|(name, value)| (format!("{}{}", prefix, name), value))
+            .unzip();
+        (names.join("\t"), values.join("\t"))
+    }
+}
+
 pub struct CounterManager {
-    pub counter: HashMap<String, u32>,
+    pub counter: HashMap<String, u64>,
     pub validname_counter: HashMap<String, HashMap<String, HashMap<String, u32>>>,
     pub validtype_counter: HashMap<String, HashMap<String, HashMap<String, u32>>>,
     outdir: String,
     //NOTE 2024-12-5：add new fields to store before and after reads info
-    total_reads: u32,
-    total_bases: u32,
-    before_gc_content: f64,
-    valid_reads: u32,
-    valid_bases: u32,
-    after_gc_content: f64,
+    before_qc: QcStats,
+    after_qc: QcStats,
+    sample_qc: HashMap<String, QcStats>,
+    rescued_reads: u64,
+    pub skipped_reads: u64,
 }
 impl CounterManager {
     pub fn new(outdir: String) -> CounterManager {
@@ -26,36 +150,42 @@ impl CounterManager {
         counter.insert("unknown".to_string(), 0);
         counter.insert("fusion".to_string(), 0);
         CounterManager {
-            counter: counter,
+            counter,
             validname_counter: HashMap::new(),
             validtype_counter: HashMap::new(),
             // names: vec!["total".to_string(),"filtered".to_string(), "unknown".to_string(), "valid".to_string()],
-            outdir: outdir,
-            total_reads: 0,
-            total_bases: 0,
-            before_gc_content: 0.5,
-            valid_reads: 0,
-            valid_bases: 0,
-            after_gc_content: 0.5,
+            outdir,
+            before_qc: QcStats::default(),
+            after_qc: QcStats::default(),
+            sample_qc: HashMap::new(),
+            rescued_reads: 0,
+            skipped_reads: 0,
         }
     }
     pub fn counter_read(&mut self, readinfo: &ReadInfo) {
-        self.total_reads += 1;
-        self.total_bases += readinfo.read_len as u32;
+        self.before_qc.add(readinfo.record.seq(), readinfo.record.qual());
         *self.counter.entry(readinfo.read_type.clone()).or_insert(0) += 1;
+        if readinfo.rescued {
+            self.rescued_reads += 1;
+        }
         if readinfo.read_type == "valid" {
-            self.valid_reads += 1;
-            self.valid_bases += readinfo.read_len as u32;
+            // after stats use the trimmed output record
+            let out_record = &readinfo.out_record;
+            self.after_qc.add(out_record.seq(), out_record.qual());
+            self.after_qc.add_qtrim(readinfo.qtrim_bases);
+            let sample_qc = self.sample_qc.entry(readinfo.outfile.clone()).or_default();
+            sample_qc.add(out_record.seq(), out_record.qual());
+            sample_qc.add_qtrim(readinfo.qtrim_bases);
             let primer: String = readinfo.match_names[0].clone();
             let index = readinfo.match_names[1].clone();
             let barcode = readinfo.match_names[2].clone();
             let primer_type = readinfo.match_types[0].clone();
             let index_type = readinfo.match_types[1].clone();
             let barcode_type = readinfo.match_types[2].clone();
-            let barcode_map = self.validname_counter.entry(barcode).or_insert_with(HashMap::new);
-            let barcodetype_map = self.validtype_counter.entry(barcode_type).or_insert_with(HashMap::new);
-            let index_map = barcode_map.entry(index).or_insert_with(HashMap::new);
-            let indextype_map = barcodetype_map.entry(index_type).or_insert_with(HashMap::new);
+            let barcode_map = self.validname_counter.entry(barcode).or_default();
+            let barcodetype_map = self.validtype_counter.entry(barcode_type).or_default();
+            let index_map = barcode_map.entry(index).or_default();
+            let indextype_map = barcodetype_map.entry(index_type).or_default();
             *indextype_map.entry(primer_type).or_insert(0) += 1;
             *index_map.entry(primer).or_insert(0) += 1;
         }
@@ -84,15 +214,24 @@ impl CounterManager {
             }
         }
     }
-    // pub fn write_filter_info(&self) {
-    //     // TODO use to write filter reads info
-    //     ()
-    // }
+    fn filtered_reads(&self) -> u64 {
+        self.counter.iter().filter(|(read_type, _)| read_type.starts_with("filtered")).map(|(_, count)| count).sum()
+    }
+    pub fn write_filter_info(&self) {
+        // every reason a read is not valid: filters, unknown, fusion, off_target and dimer
+        let mut file = File::create(Path::new(&self.outdir).join("filter_info.tsv")).expect("fail to create filter_info.tsv");
+        writeln!(file, "reason\tcount").expect("fail to write header");
+        let mut reasons: Vec<_> = self.counter.iter().filter(|(read_type, _)| *read_type != "valid").collect();
+        reasons.sort();
+        for (reason, count) in reasons {
+            writeln!(file, "{}\t{}", reason, count).expect("fail to write filter_info");
+        }
+    }
     pub fn info(&self){
-        let valid_reads = self.valid_reads as f64;
-        let total_reads = self.total_reads as f64;
+        let valid_reads = self.after_qc.reads as f64;
+        let total_reads = self.before_qc.reads as f64;
         let fusion = self.counter.get("fusion").unwrap_or(&0);
-        let filterd = self.counter.get("filtered").unwrap_or(&0);
+        let filterd = &self.filtered_reads();
         let valid_rate = if total_reads > 0.0 {
             100.0 * valid_reads / total_reads
         } else {
@@ -111,6 +250,12 @@ impl CounterManager {
         info!("process {}/{} reads (filtered/total), filtered rate: {:.2} %.", filterd, total_reads, filterd_rate);
         info!("process {}/{} reads (fusion/total), fusion rate: {:.2} %.", fusion, total_reads, fusion_rate);
         info!("process {}/{} reads (valid/total), valid rate: {:.2} %.", valid_reads, total_reads, valid_rate);
+        if self.skipped_reads > 0 {
+            info!("skip {} malformed fastq records.", self.skipped_reads);
+        }
+        if self.rescued_reads > 0 {
+            info!("process {}/{} reads (rescued/valid) by rescue pass.", self.rescued_reads, valid_reads);
+        }
     }
     // pub fn write_total_info(&self) {
     //     let mut file = File::create(Path::new(&self.outdir).join("total_info.tsv")).expect("fail to create total_info.tsv");
@@ -119,25 +264,11 @@ impl CounterManager {
     //         writeln!(file, "{}\t{}", read_type, count).expect("fail to write read info");
     //     }
     // }
-    pub fn write_total_info(&self) {
-        let total_reads = self.total_reads as f64;
-        let valid_reads = self.valid_reads as f64;
-        let total_bases = self.total_bases as f64;
-        let valid_bases = self.valid_bases as f64;
-
-        let before_read1_mean_length = if total_reads > 0.0 {
-            total_bases / total_reads
-        } else {
-            0.0
-        };
-        let after_read1_mean_length = if valid_reads > 0.0 {
-            valid_bases / valid_reads
-        } else {
-            0.0
-        };
+    pub fn write_total_info(&self, db_meta: &[DbMeta]) {
+        let total_reads = self.before_qc.reads as f64;
         let valid_reads = *self.counter.get("valid").unwrap_or(&0) as f64;
         let unkown_reads = *self.counter.get("unknown").unwrap_or(&0) as f64;
-        let filtered_reads = *self.counter.get("filtered").unwrap_or(&0) as f64;
+        let filtered_reads = self.filtered_reads() as f64;
         let fusion_reads = *self.counter.get("fusion").unwrap_or(&0) as f64;
 
         let valid_rate = if total_reads > 0.0 {
@@ -162,23 +293,79 @@ impl CounterManager {
         };
 
         let mut file = File::create(Path::new(&self.outdir).join("total_info.tsv")).expect("fail to create total_info.tsv");
-        writeln!(file, "total\ttotal_bases\tbefore_read1_mean_length\tafter_read1_mean_length\tbefore_gc_content\tafter_gc_content\tfiltered\tfiltered_rate\tfuison\tfusion_rate\tunkown\tunkown_rate\tvalid_reads\tvalid_bases\tvalid_rate").expect("fail to write header");
-        writeln!(file, "{}\t{}\t{:.1}\t{:.1}\t{:.1}\t{:.1}\t{}\t{:.2}\t{}\t{:.2}\t{}\t{:.2}\t{}\t{}\t{:.2}", 
-            total_reads as u32, 
-            total_bases as u32, 
-            before_read1_mean_length,
-            after_read1_mean_length,
-            self.before_gc_content,
-            self.after_gc_content,
-            filtered_reads as u32,
+        // the original columns come first and keep their names, the before/after statistics follow,
+        // their gc content is already in before_gc_content and after_gc_content
+        let (before_header, before_values) = self.before_qc.columns("before_", &["gc_content"]);
+        let (after_header, after_values) = self.after_qc.columns("after_", &["gc_content"]);
+        writeln!(file, "total\ttotal_bases\tbefore_read1_mean_length\tafter_read1_mean_length\tbefore_gc_content\tafter_gc_content\tfiltered\tfiltered_rate\tfuison\tfusion_rate\tunkown\tunkown_rate\tvalid_reads\tvalid_bases\tvalid_rate\t{}\t{}\tqtrim_left_bases\tqtrim_right_bases\trescued_reads\tskipped_reads\tdb_kit\tdb_version\tdb_date\tdb_sha256\tdb_verified",
+            before_header,
+            after_header,
+        ).expect("fail to write header");
+        writeln!(file, "{}\t{}\t{:.1}\t{:.1}\t{:.4}\t{:.4}\t{}\t{:.2}\t{}\t{:.2}\t{}\t{:.2}\t{}\t{}\t{:.2}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            self.before_qc.reads,
+            self.before_qc.bases,
+            self.before_qc.mean_length(),
+            self.after_qc.mean_length(),
+            self.before_qc.gc_content(),
+            self.after_qc.gc_content(),
+            filtered_reads as u64,
             filtered_rate,
-            fusion_reads as u32,
+            fusion_reads as u64,
             fusion_rate,
-            unkown_reads as u32,
+            unkown_reads as u64,
             unkown_rate,
-            valid_reads as u32, 
-            valid_bases as u32, 
-            valid_rate, 
+            valid_reads as u64,
+            self.after_qc.bases,
+            valid_rate,
+            before_values,
+            after_values,
+            self.after_qc.qtrim_bases.0,
+            self.after_qc.qtrim_bases.1,
+            self.rescued_reads,
+            self.skipped_reads,
+            // one value per pattern db, comma separated
+            db_meta.iter().map(|meta| meta.kit.as_str()).collect::<Vec<_>>().join(","),
+            db_meta.iter().map(|meta| meta.version.as_str()).collect::<Vec<_>>().join(","),
+            db_meta.iter().map(|meta| meta.date.as_str()).collect::<Vec<_>>().join(","),
+            db_meta.iter().map(|meta| meta.sha256.as_str()).collect::<Vec<_>>().join(","),
+            db_meta.iter().map(|meta| meta.verified().to_string()).collect::<Vec<_>>().join(","),
         ).expect("fail to write total_info");
     }
+    pub fn write_qc_info(&self) {
+        let mut file = File::create(Path::new(&self.outdir).join("sample_qc.tsv")).expect("fail to create sample_qc.tsv");
+        writeln!(file, "sample\t{}\tqtrim_left_bases\tqtrim_right_bases", QcStats::header("")).expect("fail to write header");
+        let mut samples: Vec<_> = self.sample_qc.iter().collect();
+        samples.sort_by(|a, b| a.0.cmp(b.0));
+        for (sample, qc) in samples {
+            writeln!(file, "{}\t{}\t{}\t{}", sample, qc.to_tsv(), qc.qtrim_bases.0, qc.qtrim_bases.1).expect("fail to write sample_qc");
+        }
+        let mut file = File::create(Path::new(&self.outdir).join("length_hist.tsv")).expect("fail to create length_hist.tsv");
+        writeln!(file, "length_bin\tbefore_reads\tafter_reads").expect("fail to write header");
+        let before_hist = self.before_qc.binned_length_hist();
+        let after_hist = self.after_qc.binned_length_hist();
+        let mut bins: Vec<_> = before_hist.keys().chain(after_hist.keys()).copied().collect();
+        bins.sort_unstable();
+        bins.dedup();
+        for bin in bins {
+            writeln!(file, "{}-{}\t{}\t{}", bin, bin + LENGTH_BIN, before_hist.get(&bin).unwrap_or(&0), after_hist.get(&bin).unwrap_or(&0)).expect("fail to write length_hist");
+        }
+    }
+}
+
+#[test]
+pub fn test_qc_stats(){
+    let mut qc = QcStats::default();
+    // 300, 300 and 400 bp reads at Q10, Q40 and Q20
+    for (len, qual) in [(300, b'+'), (300, b'I'), (400, b'5')] {
+        qc.add(&vec![b'A'; len], &vec![qual; len]);
+    }
+    assert_eq!(qc.bases, 1000);
+    // the longest read holds 400 of 1000 bases, with a 300 bp read half of them are reached
+    assert_eq!(qc.n50(), 300);
+    assert_eq!(qc.median_qual(), 20.0);
+    qc.add(&[b'A'; 2000], &[b'I'; 2000]);
+    assert_eq!(qc.n50(), 2000);
+    assert_eq!(qc.median_qual(), 20.0);
+    assert_eq!(QcStats::header("after_").split('\t').count(), QC_COLUMNS.len());
+    assert_eq!(qc.columns("", &["gc_content"]).1.split('\t').count(), QC_COLUMNS.len() - 1);
 }
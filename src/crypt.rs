@@ -0,0 +1,192 @@
+use crate::error::{JasperError, Result};
+use age::secrecy::SecretString;
+use log::info;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+/// environment variable holding the passphrase of `.safe` files
+pub const PASSPHRASE_ENV: &str = "JASPER_PASSPHRASE";
+/// environment variable holding the new passphrase of `db rekey`
+pub const NEW_PASSPHRASE_ENV: &str = "JASPER_NEW_PASSPHRASE";
+// asked once per run, every passphrase encrypted file of the run shares it
+static PASSPHRASE: OnceLock<String> = OnceLock::new();
+
+/// Where the key of a `.safe` file comes from: age identity files, a passphrase file,
+/// the JASPER_PASSPHRASE environment variable or a prompt on the terminal, in this order.
+#[derive(Debug, Clone, Default)]
+pub struct KeySource {
+    pub passphrase_file: Option<String>,
+    pub identity_files: Vec<String>,
+}
+impl KeySource {
+    pub fn new(passphrase_file: Option<String>, identity_files: Vec<String>) -> KeySource {
+        KeySource {
+            passphrase_file,
+            identity_files,
+        }
+    }
+    pub fn decrypt(&self, file: &str, content: &[u8]) -> Result<Vec<u8>> {
+        let decryptor = age::Decryptor::new_buffered(content).map_err(|e| JasperError::decrypt(file, e))?;
+        let mut reader = if decryptor.is_scrypt() {
+            let identity = age::scrypt::Identity::new(self.passphrase(file)?);
+            decryptor.decrypt(std::iter::once(&identity as &dyn age::Identity))
+        } else {
+            if self.identity_files.is_empty() {
+                return Err(JasperError::decrypt(file, "encrypted to age recipients, set the identity file with --identity"));
+            }
+            let identities = self.identities()?;
+            decryptor.decrypt(identities.iter().map(|identity| identity.as_ref()))
+        }
+        .map_err(|e| JasperError::decrypt(file, e))?;
+        let mut decrypted = Vec::new();
+        reader.read_to_end(&mut decrypted).map_err(|e| JasperError::decrypt(file, e))?;
+        Ok(decrypted)
+    }
+    fn identities(&self) -> Result<Vec<Box<dyn age::Identity>>> {
+        let mut identities = Vec::new();
+        for identity_file in &self.identity_files {
+            let file_identities = age::IdentityFile::from_file(identity_file.clone())
+                .map_err(|e| JasperError::io(identity_file, e))?
+                .into_identities()
+                .map_err(|e| JasperError::decrypt(identity_file, e))?;
+            identities.extend(file_identities);
+        }
+        Ok(identities)
+    }
+    fn passphrase(&self, file: &str) -> Result<SecretString> {
+        if let Some(passphrase) = PASSPHRASE.get() {
+            return Ok(SecretString::from(passphrase.clone()));
+        }
+        let passphrase = match (&self.passphrase_file, std::env::var(PASSPHRASE_ENV)) {
+            (Some(passphrase_file), _) => read_passphrase_file(passphrase_file)?,
+            (None, Ok(passphrase)) => passphrase,
+            (None, Err(_)) => prompt_passphrase(&format!("Enter passphrase for ({}): ", file))?,
+        };
+        let _ = PASSPHRASE.set(passphrase.clone());
+        Ok(SecretString::from(passphrase))
+    }
+}
+
+/// Encrypt a file to `<file>.safe`, for the age recipients when given, otherwise with a passphrase.
+pub fn encrypt_file(file: &str, recipients: &[String], recipients_files: &[String], passphrase_file: Option<String>) -> Result<()> {
+    let content = std::fs::read(file).map_err(|e| JasperError::io(file, e))?;
+    let encrypted = encrypt(file, &content, recipients, recipients_files, || new_passphrase(file, passphrase_file, PASSPHRASE_ENV))?;
+    let output_file = format!("{}.safe", file);
+    std::fs::write(&output_file, encrypted).map_err(|e| JasperError::io(&output_file, e))?;
+    info!("Encrypted pattern db file saved to {}", output_file);
+    Ok(())
+}
+
+/// Decrypt a `.safe` file to `output`, default is the file name without `.safe`.
+pub fn decrypt_file(file: &str, output: Option<String>, key_source: &KeySource) -> Result<()> {
+    let output_file = output.unwrap_or_else(|| file.trim_end_matches(".safe").to_string());
+    if output_file == file || Path::new(&output_file).exists() {
+        return Err(JasperError::Config(format!("output file ({}) already exists, set another one with --output", output_file)));
+    }
+    let content = std::fs::read(file).map_err(|e| JasperError::io(file, e))?;
+    std::fs::write(&output_file, key_source.decrypt(file, &content)?).map_err(|e| JasperError::io(&output_file, e))?;
+    info!("Decrypted file saved to {}", output_file);
+    Ok(())
+}
+
+/// Re-encrypt a `.safe` file in place for new recipients or with a new passphrase.
+pub fn rekey_file(file: &str, key_source: &KeySource, recipients: &[String], recipients_files: &[String], new_passphrase_file: Option<String>) -> Result<()> {
+    let content = std::fs::read(file).map_err(|e| JasperError::io(file, e))?;
+    let decrypted = key_source.decrypt(file, &content)?;
+    let encrypted = encrypt(file, &decrypted, recipients, recipients_files, || new_passphrase(file, new_passphrase_file, NEW_PASSPHRASE_ENV))?;
+    // write aside and rename, the old file stays intact if anything fails
+    let tmp_file = format!("{}.rekey", file);
+    std::fs::write(&tmp_file, encrypted).map_err(|e| JasperError::io(&tmp_file, e))?;
+    std::fs::rename(&tmp_file, file).map_err(|e| JasperError::io(file, e))?;
+    info!("Re-keyed file saved to {}", file);
+    Ok(())
+}
+
+fn encrypt(file: &str, content: &[u8], recipients: &[String], recipients_files: &[String], passphrase: impl FnOnce() -> Result<String>) -> Result<Vec<u8>> {
+    let mut recipient_keys: Vec<String> = recipients.to_vec();
+    for recipients_file in recipients_files {
+        let reader = BufReader::new(File::open(recipients_file).map_err(|e| JasperError::io(recipients_file, e))?);
+        recipient_keys.extend(
+            reader
+                .lines()
+                .map_while(std::result::Result::ok)
+                .map(|line| line.trim().to_string())
+                .filter(|line| !line.is_empty() && !line.starts_with('#')),
+        );
+    }
+    let encryptor = if recipient_keys.is_empty() {
+        age::Encryptor::with_user_passphrase(SecretString::from(passphrase()?))
+    } else {
+        let recipients = recipient_keys
+            .iter()
+            .map(|key| age::x25519::Recipient::from_str(key).map_err(|e| JasperError::Config(format!("invalid age recipient ({}): {}", key, e))))
+            .collect::<Result<Vec<age::x25519::Recipient>>>()?;
+        age::Encryptor::with_recipients(recipients.iter().map(|recipient| recipient as &dyn age::Recipient))
+            .map_err(|e| JasperError::Config(format!("fail to encrypt ({}): {}", file, e)))?
+    };
+    let mut encrypted = Vec::new();
+    let mut writer = encryptor.wrap_output(&mut encrypted).map_err(|e| JasperError::io(file, e))?;
+    writer.write_all(content).map_err(|e| JasperError::io(file, e))?;
+    writer.finish().map_err(|e| JasperError::io(file, e))?;
+    Ok(encrypted)
+}
+
+fn new_passphrase(file: &str, passphrase_file: Option<String>, env: &str) -> Result<String> {
+    match (passphrase_file, std::env::var(env)) {
+        (Some(passphrase_file), _) => read_passphrase_file(&passphrase_file),
+        (None, Ok(passphrase)) => Ok(passphrase),
+        (None, Err(_)) => {
+            let passphrase = prompt_passphrase(&format!("Enter new passphrase for ({}): ", file))?;
+            if prompt_passphrase("Confirm passphrase: ")? != passphrase {
+                return Err(JasperError::Config("passphrases do not match".to_string()));
+            }
+            Ok(passphrase)
+        }
+    }
+}
+
+fn read_passphrase_file(passphrase_file: &str) -> Result<String> {
+    let content = std::fs::read_to_string(passphrase_file).map_err(|e| JasperError::io(passphrase_file, e))?;
+    Ok(content.lines().next().unwrap_or_default().to_string())
+}
+
+fn prompt_passphrase(prompt: &str) -> Result<String> {
+    // ask on the terminal, stdin and stdout may carry reads
+    let no_tty = |_| JasperError::Config(format!("no terminal to ask the passphrase, use --passphrase-file or {}", PASSPHRASE_ENV));
+    let mut tty = File::options().read(true).write(true).open("/dev/tty").map_err(no_tty)?;
+    let tty_in = tty.try_clone().map_err(no_tty)?;
+    let stty = |arg: &str| {
+        if let Ok(tty_in) = tty_in.try_clone() {
+            let _ = Command::new("stty").arg(arg).stdin(Stdio::from(tty_in)).status();
+        }
+    };
+    write!(tty, "{}", prompt).and_then(|_| tty.flush()).map_err(no_tty)?;
+    stty("-echo");
+    let mut passphrase = String::new();
+    let result = BufReader::new(tty_in.try_clone().map_err(no_tty)?).read_line(&mut passphrase);
+    stty("echo");
+    let _ = writeln!(tty);
+    result.map_err(no_tty)?;
+    Ok(passphrase.trim_end_matches(['\r', '\n']).to_string())
+}
+
+#[test]
+pub fn test_recipient_roundtrip(){
+    use age::secrecy::ExposeSecret;
+    let dir = std::env::temp_dir().join(format!("jasper_crypt_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let identity = age::x25519::Identity::generate();
+    let identity_file = dir.join("site.key");
+    std::fs::write(&identity_file, identity.to_string().expose_secret()).unwrap();
+    let db = dir.join("kit.db");
+    std::fs::write(&db, "CB01\tACGT\n").unwrap();
+    encrypt_file(db.to_str().unwrap(), &[identity.to_public().to_string()], &[], None).unwrap();
+    let safe = format!("{}.safe", db.display());
+    let key_source = KeySource::new(None, vec![identity_file.display().to_string()]);
+    assert_eq!(key_source.decrypt(&safe, &std::fs::read(&safe).unwrap()).unwrap(), b"CB01\tACGT\n");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
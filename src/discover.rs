@@ -0,0 +1,272 @@
+use crate::error::Result;
+use crate::fastq::ReadInfo;
+use crate::pattern::PatternArgs;
+use crate::utils::reverse_complement;
+use bio::pattern_matching::myers::{Myers, MyersBuilder};
+use log::info;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::thread;
+
+const KMER_SIZE: usize = 12;
+const TOP_KMERS: usize = 50;
+// db entries carried by at least this fraction of unknown reads are suggested in the log
+const SUGGEST_RATE: f64 = 0.01;
+// and only when the carrier count exceeds the background by this many standard deviations
+const SUGGEST_Z: f64 = 3.0;
+// entries not in the lists shorter than this hit random windows with a single error,
+// they are matched exactly and only in the narrowest level window
+const SHORT_PATTERN: usize = 16;
+
+/// A pattern db entry with the tolerance and windows it is searched with.
+struct DiscoverEntry {
+    name: String,
+    seq: String,
+    in_list: bool,
+    max_dist: (u8, u8),                // max distance in the left and right window
+    window_size: (usize, usize),       // window of its level, the widest or narrowest (short) window if not in a list
+    alias_of: Option<String>,          // listed entry with the same sequence on either strand
+}
+impl DiscoverEntry {
+    fn new(name: &str, seq: &str, patternargs: &PatternArgs) -> DiscoverEntry {
+        let len = seq.trim_matches('N').len();
+        let max_dist = |errate: f32| (len as f32 * errate).floor() as u8;
+        // an entry in a list is searched as on its first level, with the per pattern tolerance of the list
+        let level = patternargs
+            .pattern_vec
+            .iter()
+            .find(|patternarg| patternarg.pattern_db.f_patterns.contains_key(name));
+        match level {
+            Some(patternarg) => {
+                let patterndb = &patternarg.pattern_db;
+                let errate = patterndb.pattern_errate.get(name).copied().unwrap_or(patternarg.pattern_errate);
+                let dist = match patterndb.pattern_max_dist.get(name) {
+                    Some(max_dist) => (*max_dist, *max_dist),
+                    None => (max_dist(errate.0), max_dist(errate.1)),
+                };
+                DiscoverEntry { name: name.to_string(), seq: seq.to_string(), in_list: true, max_dist: dist, window_size: patternarg.window_size, alias_of: None }
+            }
+            None => {
+                // the strictest errate of the loaded levels, short entries must match exactly
+                let errate = patternargs
+                    .pattern_vec
+                    .iter()
+                    .map(|patternarg| patternarg.pattern_errate.0.min(patternarg.pattern_errate.1))
+                    .fold(f32::MAX, f32::min);
+                let windows = patternargs.pattern_vec.iter().map(|patternarg| patternarg.window_size);
+                let (dist, window_size) = if len < SHORT_PATTERN {
+                    (0, windows.fold((usize::MAX, usize::MAX), |acc, window| (acc.0.min(window.0), acc.1.min(window.1))))
+                } else {
+                    (max_dist(errate), windows.fold((0, 0), |acc, window| (acc.0.max(window.0), acc.1.max(window.1))))
+                };
+                DiscoverEntry { name: name.to_string(), seq: seq.to_string(), in_list: false, max_dist: (dist, dist), window_size, alias_of: None }
+            }
+        }
+    }
+}
+
+/// Collect the read-end windows of unknown reads and match them back against the whole pattern db.
+pub struct DiscoverManager {
+    outdir: String,
+    window_size: (usize, usize),
+    max_reads: usize,
+    threads: usize,
+    entries: Vec<DiscoverEntry>,
+    windows: Vec<(Vec<u8>, Vec<u8>)>,
+    kmer_counter: HashMap<Vec<u8>, u64>,
+}
+impl DiscoverManager {
+    pub fn new(outdir: String, patternargs: &PatternArgs, pattern_db: HashMap<String, String>, max_reads: usize, threads: usize) -> DiscoverManager {
+        // the widest window any level searches
+        let window_size = patternargs.pattern_vec.iter().fold((0, 0), |acc, patternarg| {
+            (acc.0.max(patternarg.window_size.0), acc.1.max(patternarg.window_size.1))
+        });
+        let mut entries: Vec<DiscoverEntry> = pattern_db
+            .iter()
+            .map(|(name, seq)| DiscoverEntry::new(name, seq.trim(), patternargs))
+            .collect();
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        // an entry with the sequence of a listed entry is an alias, it is reported with the listed entry's counts
+        let mut listed: HashMap<String, String> = HashMap::new();
+        for entry in entries.iter().filter(|entry| entry.in_list) {
+            let seq = entry.seq.to_ascii_uppercase();
+            listed.entry(reverse_complement(&seq)).or_insert_with(|| entry.name.clone());
+            listed.entry(seq).or_insert_with(|| entry.name.clone());
+        }
+        for entry in entries.iter_mut().filter(|entry| !entry.in_list) {
+            entry.alias_of = listed.get(&entry.seq.to_ascii_uppercase()).cloned();
+        }
+        DiscoverManager {
+            outdir,
+            window_size,
+            max_reads,
+            threads: threads.max(1),
+            entries,
+            windows: Vec::new(),
+            kmer_counter: HashMap::new(),
+        }
+    }
+    pub fn add(&mut self, readinfo: &ReadInfo) {
+        if readinfo.read_type != "unknown" || self.windows.len() >= self.max_reads {
+            return;
+        }
+        let seq = readinfo.record.seq();
+        let left = &seq[..self.window_size.0.min(seq.len())];
+        let right = &seq[seq.len().saturating_sub(self.window_size.1)..];
+        // count each canonical k-mer once per read, so a repeat inside one read is not over-represented
+        let mut kmers = HashSet::new();
+        for window in [left, right] {
+            for kmer in window.windows(KMER_SIZE) {
+                if !kmer.contains(&b'N') {
+                    kmers.insert(canonical_kmer(kmer));
+                }
+            }
+        }
+        for kmer in kmers {
+            *self.kmer_counter.entry(kmer).or_insert(0) += 1;
+        }
+        self.windows.push((left.to_vec(), right.to_vec()));
+    }
+    pub fn write_discover_info(&self) -> Result<()> {
+        let unknown_reads = self.windows.len();
+        // short patterns hit random windows by chance, the reversed (not complemented) pattern keeps
+        // length and composition and is used as the background of each entry, exact matches are
+        // also held against the reads expected to carry a random sequence of that length
+        let scored: Vec<&DiscoverEntry> = self.entries.iter().filter(|entry| entry.alias_of.is_none()).collect();
+        let chunk_size = scored.len().div_ceil(self.threads).max(1);
+        let mut hits: Vec<(&DiscoverEntry, u64, u64)> = thread::scope(|scope| {
+            let handles: Vec<_> = scored
+                .chunks(chunk_size)
+                .map(|chunk| {
+                    scope.spawn(move || {
+                        chunk
+                            .iter()
+                            .filter_map(|&entry| {
+                                let count = self.count_carriers(entry, &entry.seq)?;
+                                let background = if count > 0 { self.count_carriers(entry, &entry.seq.chars().rev().collect::<String>())? } else { 0 };
+                                let background = background.max(self.random_carriers(entry));
+                                Some((entry, count, background))
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            handles.into_iter().flat_map(|handle| handle.join().expect("discover thread panicked")).collect()
+        });
+        let listed_hits: HashMap<&str, (u64, u64)> = hits.iter().map(|hit| (hit.0.name.as_str(), (hit.1, hit.2))).collect();
+        for entry in self.entries.iter() {
+            if let Some(&(count, background)) = entry.alias_of.as_ref().and_then(|name| listed_hits.get(name.as_str())) {
+                hits.push((entry, count, background));
+            }
+        }
+        hits.sort_by(|a, b| (b.1.saturating_sub(b.2)).cmp(&a.1.saturating_sub(a.2)).then(a.0.name.cmp(&b.0.name)));
+
+        let rate = |count: u64| if unknown_reads > 0 { count as f64 / unknown_reads as f64 } else { 0.0 };
+        let mut file = File::create(Path::new(&self.outdir).join("discover_info.tsv")).expect("fail to create discover_info.tsv");
+        writeln!(file, "pattern\tseq\tin_list\treads\trate\tbackground_rate\talias_of").expect("fail to write header");
+        for (entry, count, background) in hits.iter().filter(|hit| hit.1 > 0) {
+            let alias_of = entry.alias_of.as_deref().unwrap_or("-");
+            writeln!(file, "{}\t{}\t{}\t{}\t{:.4}\t{:.4}\t{}", entry.name, entry.seq, entry.in_list, count, rate(*count), rate(*background), alias_of).expect("fail to write discover_info");
+        }
+        let enriched = |count: u64, background: u64| {
+            rate(count) - rate(background) >= SUGGEST_RATE
+                && count >= 2 * background
+                && (count - background) as f64 >= SUGGEST_Z * ((background + 1) as f64).sqrt()
+        };
+        for (entry, count, _) in hits.iter().filter(|hit| !hit.0.in_list && hit.0.alias_of.is_none() && enriched(hit.1, hit.2)) {
+            info!("~{:.1}% of unknown reads carry {} which is not in your list.", 100.0 * rate(*count), entry.name);
+        }
+
+        let mut kmers: Vec<_> = self.kmer_counter.iter().collect();
+        kmers.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        let mut file = File::create(Path::new(&self.outdir).join("discover_kmer.tsv")).expect("fail to create discover_kmer.tsv");
+        writeln!(file, "kmer\treads\trate\tpatterns").expect("fail to write header");
+        for (kmer, count) in kmers.into_iter().take(TOP_KMERS) {
+            let kmer = String::from_utf8_lossy(kmer).to_string();
+            let kmer_rc = reverse_complement(&kmer);
+            let names: Vec<&str> = self
+                .entries
+                .iter()
+                .filter(|entry| entry.seq.contains(&kmer) || entry.seq.contains(&kmer_rc))
+                .map(|entry| entry.name.as_str())
+                .collect();
+            writeln!(file, "{}\t{}\t{:.4}\t{}", kmer, count, rate(*count), names.join(",")).expect("fail to write discover_kmer");
+        }
+        info!("discover report of {} unknown reads written to discover_info.tsv and discover_kmer.tsv.", unknown_reads);
+        Ok(())
+    }
+    fn random_carriers(&self, entry: &DiscoverEntry) -> u64 {
+        if entry.max_dist != (0, 0) {
+            return 0;
+        }
+        let len = entry.seq.bytes().filter(|base| *base != b'N').count();
+        let hit = 2.0 * 0.25f64.powi(len as i32);
+        let miss = |window: &[u8], window_size: usize| {
+            let positions = window.len().min(window_size) + 1;
+            (1.0 - hit).powi(positions.saturating_sub(entry.seq.len()) as i32)
+        };
+        let expected: f64 = self
+            .windows
+            .iter()
+            .map(|(left, right)| 1.0 - miss(left, entry.window_size.0) * miss(right, entry.window_size.1))
+            .sum();
+        expected.ceil() as u64
+    }
+    fn count_carriers(&self, entry: &DiscoverEntry, seq: &str) -> Option<u64> {
+        // number of collected reads carrying the pattern or its reverse complement in either window of its level
+        if seq.trim_matches('N').is_empty() || seq.len() > 64 {
+            return None;
+        }
+        let seq_rc = reverse_complement(seq);
+        let exact = !seq.contains('N');
+        let myers = |seq: &str| MyersBuilder::new().ambig(b'N', b"ACGT").build_64(seq.bytes());
+        let (fwd, rev) = (myers(seq), myers(&seq_rc));
+        let carried = |window: &[u8], max_dist: u8| {
+            if exact && max_dist == 0 {
+                window.windows(seq.len()).any(|text| text == seq.as_bytes() || text == seq_rc.as_bytes())
+            } else {
+                find(&fwd, window, max_dist) || find(&rev, window, max_dist)
+            }
+        };
+        let count = self
+            .windows
+            .iter()
+            .filter(|(left, right)| {
+                let left = &left[..entry.window_size.0.min(left.len())];
+                let right = &right[right.len().saturating_sub(entry.window_size.1)..];
+                carried(left, entry.max_dist.0) || carried(right, entry.max_dist.1)
+            })
+            .count();
+        Some(count as u64)
+    }
+}
+
+fn find(myers: &Myers<u64>, window: &[u8], max_dist: u8) -> bool {
+    myers.find_all_end(window, max_dist).next().is_some()
+}
+
+fn canonical_kmer(kmer: &[u8]) -> Vec<u8> {
+    let kmer_rc = reverse_complement(&String::from_utf8_lossy(kmer)).into_bytes();
+    kmer.to_vec().min(kmer_rc)
+}
+
+#[test]
+pub fn test_discover_entry() {
+    use clap::Parser;
+    let args = crate::args::Args::parse_from(["jasper", "-p", "example/cyclone_barcode.list", "-d", "example/pattern.db", "-e", "0.12,0.2", "-w", "300,200"]);
+    let mut patternargs = crate::pattern::get_patterns(&args).unwrap();
+    let pattern_db = std::mem::take(&mut patternargs.pattern_db);
+    let manager = DiscoverManager::new(String::new(), &patternargs, pattern_db, 10, 1);
+    let entry = |name: &str| manager.entries.iter().find(|entry| entry.name == name).unwrap();
+    // CB08 is searched with its level errate on each side, 9-index18 is short and not in the list
+    assert!(entry("CB08").in_list);
+    assert_eq!(entry("CB08").max_dist, (3, 5));
+    assert!(!entry("9-index18").in_list);
+    assert_eq!(entry("9-index18").max_dist, (0, 0));
+    assert_eq!(entry("Cindex12").max_dist, (3, 3));
+    // Cindex12 has the sequence of the listed CB02
+    assert_eq!(entry("Cindex12").alias_of.as_deref(), Some("CB02"));
+    assert_eq!(entry("Cindex106").alias_of, None);
+}
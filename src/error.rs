@@ -0,0 +1,69 @@
+use std::io;
+use thiserror::Error;
+
+/// Errors of bad input files and options, every kind exits with its own code.
+#[derive(Debug, Error)]
+pub enum JasperError {
+    #[error("fail to read ({file}): {source}")]
+    Io { file: String, source: io::Error },
+    #[error("({file}) line {line}: {msg}")]
+    Parse { file: String, line: u64, msg: String },
+    #[error("({file}) line {line}: no such pattern ({name}) in pattern db ({db})")]
+    MissingPattern { file: String, line: u64, name: String, db: String },
+    #[error("fail to decrypt ({file}): {msg}")]
+    Decrypt { file: String, msg: String },
+    #[error("checksum mismatch of pattern db ({file}): header sha256:{declared}, content sha256:{computed}")]
+    Checksum { file: String, declared: String, computed: String },
+    #[error("pattern ({name}) has different sequences in pattern db ({file1}) and ({file2})")]
+    DbConflict { name: String, file1: String, file2: String },
+    #[error("({file}) record {record}: malformed fastq record: {msg}")]
+    Fastq { file: String, record: u64, msg: String },
+    #[error("{0} pattern pairs lie within the allowed error of each other, fix the lists or lower the errate, or start with --skip-validate")]
+    Collision(usize),
+    #[error("{0}")]
+    Config(String),
+}
+impl JasperError {
+    pub fn io(file: &str, source: io::Error) -> JasperError {
+        JasperError::Io { file: file.to_string(), source }
+    }
+    pub fn parse(file: &str, line: u64, msg: impl Into<String>) -> JasperError {
+        JasperError::Parse { file: file.to_string(), line, msg: msg.into() }
+    }
+    pub fn decrypt(file: &str, msg: impl ToString) -> JasperError {
+        JasperError::Decrypt { file: file.to_string(), msg: msg.to_string() }
+    }
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            JasperError::Io { .. } => 2,
+            JasperError::Parse { .. } => 3,
+            JasperError::MissingPattern { .. } => 4,
+            JasperError::Decrypt { .. } => 5,
+            JasperError::Checksum { .. } => 6,
+            JasperError::Fastq { .. } => 7,
+            JasperError::Collision(_) => 8,
+            JasperError::Config(_) => 9,
+            JasperError::DbConflict { .. } => 10,
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, JasperError>;
+
+#[test]
+pub fn test_exit_code(){
+    let errors = [
+        JasperError::io("a.db", io::Error::new(io::ErrorKind::NotFound, "missing")),
+        JasperError::parse("a.list", 3, "bad row"),
+        JasperError::MissingPattern { file: "a.list".to_string(), line: 2, name: "CB01".to_string(), db: "a.db".to_string() },
+        JasperError::decrypt("a.db.safe", "bad key"),
+        JasperError::Checksum { file: "a.db".to_string(), declared: "00".to_string(), computed: "11".to_string() },
+        JasperError::Fastq { file: "a.fq".to_string(), record: 5, msg: "bad record".to_string() },
+        JasperError::Collision(1),
+        JasperError::Config("bad option".to_string()),
+        JasperError::DbConflict { name: "CB01".to_string(), file1: "a.db".to_string(), file2: "b.db".to_string() },
+    ];
+    let codes: Vec<i32> = errors.iter().map(|error| error.exit_code()).collect();
+    assert_eq!(codes, (2..=10).collect::<Vec<i32>>());
+    assert_eq!(errors[1].to_string(), "(a.list) line 3: bad row");
+}
@@ -1,54 +1,57 @@
+use crate::pattern::PatternArgs;
 use crate::splitter::SplitType;
+use crate::utils::{max_homopolymer, mean_quality, quality_trim, reverse_complement_bytes};
 use bio::io::fastq::{Reader, Record};
 use flate2::read::MultiGzDecoder;
 use flume::{unbounded, Sender, Receiver};
-use log::info;
+use log::{info, warn};
+use crate::error::{JasperError, Result};
+use std::thread::JoinHandle;
 use std::ffi::OsStr;
 use std::{
     fs::File,
     io::{BufReader, Read},
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 use std::time::Instant;
 use std::collections::HashSet;
 
 const BUFSIZE: usize = 10 * 1024 * 1024;
 
-fn is_gz(path: &PathBuf) -> bool {
+fn is_gz(path: &Path) -> bool {
     match path.extension().and_then(OsStr::to_str) {
         Some(ext) => ext == "gz",
         None => false,
     }
 }
 
-pub fn spawn_reader(files: Vec<String>) -> Receiver<ReadInfo> {
+/// Read every input on its own thread, the handle returns the number of skipped malformed records.
+pub fn spawn_reader(files: Vec<String>, skip_bad_reads: bool) -> (Receiver<ReadInfo>, JoinHandle<Result<u64>>) {
     let (rtx, rrx) = unbounded();
-    std::thread::spawn(move || {
+    let handle = std::thread::spawn(move || {
         let start_time = Instant::now();
+        let mut skipped = 0;
         if files.is_empty() {
             info!("no input file, loading from stdin...");
             let stdin_handle = std::io::stdin();
-            process_file(stdin_handle, &rtx, None);
+            skipped += process_file(stdin_handle, &rtx, None, skip_bad_reads)?;
         } else {
             for file in files {
                 let path = PathBuf::from(&file);
-                if path.exists() {
-                    let raw_handle = File::open(&path)
-                        .expect(format!("Error opening input: {}", path.display()).as_str());
-                    process_file(raw_handle, &rtx, Some(path));
-                } else {
-                    panic!("File {} does not exist", path.display());
-                }
+                let raw_handle = File::open(&path).map_err(|e| JasperError::io(&file, e))?;
+                skipped += process_file(raw_handle, &rtx, Some(path), skip_bad_reads)?;
             }
         }
 
         let elapsed_time = start_time.elapsed();
-        info!("Loading Reads data done! Time elapsed: {:.4?}", elapsed_time)
+        info!("Loading Reads data done! Time elapsed: {:.4?}", elapsed_time);
+        Ok(skipped)
     });
-    rrx
+    (rrx, handle)
 }
 
-fn process_file<R: Read + 'static>(handle: R, rtx: &Sender<ReadInfo>, path: Option<PathBuf>) {
+fn process_file<R: Read + 'static>(handle: R, rtx: &Sender<ReadInfo>, path: Option<PathBuf>, skip_bad_reads: bool) -> Result<u64> {
+    let file = path.as_ref().map_or("stdin".to_string(), |path| path.display().to_string());
     let buf_handle = BufReader::with_capacity(BUFSIZE, handle);
     let maybe_decoder_handle = {
         if let Some(path) = path {
@@ -64,17 +67,29 @@ fn process_file<R: Read + 'static>(handle: R, rtx: &Sender<ReadInfo>, path: Opti
         }
     };
     let fastq_reader = Reader::new(maybe_decoder_handle);
-    for record in fastq_reader.records() {
-        let readinfo = ReadInfo::new(record.unwrap());
-        rtx.send(readinfo).expect("Error sending");
+    let mut skipped = 0;
+    for (i, record) in fastq_reader.records().enumerate() {
+        let record = match record {
+            // a broken stream can not be read further
+            Err(bio::io::fastq::Error::ReadError(e)) => return Err(JasperError::io(&file, e)),
+            Err(e) => Err(e.to_string()),
+            Ok(record) => match record.check() {
+                Ok(()) => Ok(record),
+                Err(msg) => Err(msg.to_string()),
+            },
+        };
+        match record {
+            Ok(record) => rtx.send(ReadInfo::new(record)).expect("Error sending"),
+            Err(msg) if skip_bad_reads => {
+                warn!("skip malformed fastq record {} of ({}): {}", i + 1, file, msg);
+                skipped += 1;
+            }
+            Err(msg) => return Err(JasperError::Fastq { file, record: i as u64 + 1, msg }),
+        }
     }
+    Ok(skipped)
 }
 
-
-
-
-
-
 #[derive(Debug)]
 pub struct ReadInfo {
     pub record: Record,
@@ -87,8 +102,13 @@ pub struct ReadInfo {
     pub record_id: String,
     pub write_to_fq: bool,
     pub out_record: Record,
+    pub out_tags: Vec<(&'static str, String)>,
     pub read_len: usize,
-    pub seq_window: (usize,usize)
+    pub seq_window: (usize,usize),
+    pub out_window: (usize,usize),
+    pub qtrim_bases: (usize,usize),
+    pub polya: Option<(u8, usize, usize)>, // polyA or polyT tail (base, start, end)
+    pub rescued: bool,
 }
 impl ReadInfo {
     pub fn new(record: Record) -> ReadInfo {
@@ -103,25 +123,32 @@ impl ReadInfo {
             record_id: String::new(),
             write_to_fq: false,
             out_record: Record::new(),
+            out_tags: Vec::new(),
             read_len: record.seq().len(),
-            seq_window: (0,record.seq().len())
+            seq_window: (0,record.seq().len()),
+            out_window: (0,record.seq().len()),
+            qtrim_bases: (0,0),
+            polya: None,
+            rescued: false,
         };
         readinfo
     }
-    pub fn update(&mut self, pattern_match: &Vec<String>, write_type: &String, trim_n: usize,  min_length: usize, id_sep: &String) {
-        self.update_match_names(pattern_match);
-        self.update_out_filename(write_type, id_sep);
-        self.update_read_type(min_length,trim_n);
+    pub fn update(&mut self, patternargs: &PatternArgs) {
+        self.update_match_names(&patternargs.pattern_match);
+        self.update_out_filename(&patternargs.write_type, &patternargs.id_sep);
+        self.update_read_type(patternargs);
         // debug!("read1: {}", self.to_tsv());
         // debug!("read1_self: {:?}", self);
         self.update_readseq_window();
-        self.update_write_to_fq(trim_n, id_sep);
+        self.update_amplicon_type(patternargs.dimer_len);
+        self.update_insert_type(patternargs);
+        self.update_write_to_fq(patternargs);
     }   
-    fn update_match_names(&mut self,pattern_match: &Vec<String>){
+    fn update_match_names(&mut self,pattern_match: &[String]){
         let mut strand_values: Vec<String> = Vec::new();
         for (i, split_type) in self.split_type_vec.iter().enumerate() {
             match pattern_match.get(i) {
-                // "dual" < "single" < "unknown"
+                // "complete" < "dual" < "left" < "right" < "single" < "unknown"
                 Some(element) if element >= &String::from(split_type.patter_match) => {
                     self.match_types.push(split_type.pattern_type.clone());
                     self.match_names.push(split_type.pattern_name.clone());
@@ -146,20 +173,29 @@ impl ReadInfo {
         if unique_values.len() == 1 {
             self.strand_orient = unique_values.into_iter().next().unwrap();
         }
-    }
-    fn update_out_filename(&mut self, write_type: &String, id_sep: &String){
-        if write_type == "type" {
-            let mut reversed_names = self.match_types.clone();
-            reversed_names.reverse();
-            self.outfile = reversed_names.join("/");
-            self.record_id = self.match_types.join(id_sep)
-        } else {
-            let mut reversed_names = self.match_names.clone();
-            reversed_names.reverse();
-            self.outfile = reversed_names.join("/");
-            self.record_id = self.match_names.join(id_sep)
+        // a polyA tail at the 3' end means forward strand, a polyT head means reverse strand
+        if self.strand_orient == "unknown" {
+            match self.polya {
+                Some((b'A', _, _)) => self.strand_orient = "fs".to_string(),
+                Some((b'T', _, _)) => self.strand_orient = "rs".to_string(),
+                _ => {}
+            }
         }
-
+    }
+    fn update_out_filename(&mut self, write_type: &str, id_sep: &str){
+        let names = if write_type == "type" { &self.match_types } else { &self.match_names };
+        // a sample alias from the list names the output instead of the type or pair name
+        let mut reversed_names: Vec<String> = names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| match self.split_type_vec.get(i).and_then(|split_type| split_type.alias.as_ref()) {
+                Some(alias) if name != "unknown" => alias.clone(),
+                _ => name.clone(),
+            })
+            .collect();
+        reversed_names.reverse();
+        self.outfile = reversed_names.join("/");
+        self.record_id = names.join(id_sep)
     }
     pub fn update_readseq_window(&mut self){
         if self.split_type_vec[0].left_matcher.status{
@@ -171,56 +207,273 @@ impl ReadInfo {
             self.seq_window.1 = right;
         }
     }
-    fn update_read_type(&mut self, min_length: usize, trim_n: usize){
-        if self.read_len <= min_length {
-            self.read_type = "filtered".to_string();
+    fn update_amplicon_type(&mut self, dimer_len: usize){
+        // dual matched pairs with an expected insert length, others are off_target or dimer
+        if self.read_type != "valid" {
+            return;
+        }
+        for split_type in self.split_type_vec.iter() {
+            let Some((min_len, max_len)) = split_type.insert_range else {
+                continue;
+            };
+            if split_type.patter_match != "dual" {
+                continue;
+            }
+            let insert_len = split_type.right_matcher.ystart.saturating_sub(split_type.left_matcher.yend);
+            if insert_len < dimer_len.min(min_len) {
+                self.read_type = "dimer".to_string();
+                return;
+            }
+            if insert_len < min_len || insert_len > max_len {
+                self.read_type = "off_target".to_string();
+                return;
+            }
+        }
+    }
+    fn update_insert_type(&mut self, patternargs: &PatternArgs){
+        // insert is the sequence inside the first level patterns, known after trim coordinates
+        if self.read_type != "valid" {
+            return;
+        }
+        let insert_len = self.seq_window.1.saturating_sub(self.seq_window.0);
+        if (patternargs.min_insert > 0 && insert_len < patternargs.min_insert)
+            || (patternargs.max_insert > 0 && insert_len > patternargs.max_insert) {
+            self.read_type = "filtered_insert".to_string();
+        }
+    }
+    fn update_read_type(&mut self, patternargs: &PatternArgs){
+        if let Some(reason) = self.filter_reason(patternargs) {
+            self.read_type = reason.to_string();
+        }
+        let (cutleft, cutright) = self.trim_range(patternargs.trim_n);
+        if cutleft > cutright {
+            self.read_type = "unknown".to_string();
+            self.write_to_fq = false;
+        }
+    }
+    fn filter_reason(&self, patternargs: &PatternArgs) -> Option<&'static str> {
+        // every filter has its own read_type, all of them start with "filtered"
+        let seq = self.record.seq();
+        if self.read_len <= patternargs.min_length {
+            return Some("filtered");
         }
+        if patternargs.max_length > 0 && self.read_len > patternargs.max_length {
+            return Some("filtered_maxlen");
+        }
+        if patternargs.min_qual > 0.0 && mean_quality(self.record.qual()) < patternargs.min_qual {
+            return Some("filtered_lowq");
+        }
+        let n_count = seq.iter().filter(|b| matches!(b, b'N' | b'n')).count();
+        if n_count as f64 > patternargs.max_n * self.read_len as f64 {
+            return Some("filtered_n");
+        }
+        if patternargs.max_homopolymer > 0 && max_homopolymer(seq) > patternargs.max_homopolymer {
+            return Some("filtered_homopolymer");
+        }
+        None
+    }
+    fn trim_range(&self, trim_n: usize) -> (usize, usize) {
+        // trim_n 0 cut inside the first pattern pair, trim_n n keep the n-th pattern pair
         let cutleft;
         let mut cutright;
         if trim_n == 0 {
             cutleft = self.split_type_vec[0].left_matcher.yend;
             cutright = self.split_type_vec[0].right_matcher.ystart;
-        }else {
+        }else if trim_n <= self.split_type_vec.len(){
             cutleft = self.split_type_vec[trim_n-1].left_matcher.ystart;
             cutright = self.split_type_vec[trim_n-1].right_matcher.yend;
+        }else {
+            cutleft = 0;
+            cutright = self.read_len;
         }
         if cutright == 0 {
             cutright = self.read_len
         }
-        if cutleft > cutright {
-            self.read_type = "unknown".to_string();
-            self.write_to_fq = false;
-        }
+        (cutleft, cutright)
     }
-    fn update_write_to_fq(&mut self,trim_n: usize, id_sep: &String) {
+    fn update_write_to_fq(&mut self, patternargs: &PatternArgs) {
         if self.read_type == "valid" {
             self.write_to_fq = true;
-            let cutleft;
-            let mut cutright;
-            if trim_n == 0 {
-                cutleft = self.split_type_vec[0].left_matcher.yend;
-                cutright = self.split_type_vec[0].right_matcher.ystart;
-            }else if trim_n <= self.split_type_vec.len(){
-                cutleft = self.split_type_vec[trim_n-1].left_matcher.ystart;
-                cutright = self.split_type_vec[trim_n-1].right_matcher.yend;
-            }else {
-                cutleft = 0;
-                cutright = self.read_len;
+        }
+        if !self.write_to_fq && !patternargs.stdout {
+            return;
+        }
+        let (mut cutleft, mut cutright) = self.trim_range(patternargs.trim_n);
+        if cutleft > cutright {
+            // unknown reads in stdout mode are written untrimmed
+            (cutleft, cutright) = (0, self.read_len);
+        }
+        if patternargs.softmask {
+            // keep the whole read, matched patterns are lowercased instead of cut
+            (cutleft, cutright) = (0, self.read_len);
+        } else if patternargs.trim_n == 0 {
+            // the tail sits next to the first level patterns, trim it with the insert
+            match self.polya {
+                Some((b'A', start, _)) if start < cutright && start >= cutleft => cutright = start,
+                Some((b'T', _, end)) if end > cutleft && end <= cutright => cutleft = end,
+                _ => {}
             }
-            if cutright == 0 {
-                cutright = self.read_len
+        }
+        if patternargs.qtrim != "none" {
+            let (keep_start, keep_end) = quality_trim(&self.record.qual()[cutleft..cutright], &patternargs.qtrim, patternargs.qtrim_q, patternargs.qtrim_window);
+            self.qtrim_bases = (keep_start, cutright - cutleft - keep_end);
+            (cutleft, cutright) = (cutleft + keep_start, cutleft + keep_end);
+        }
+        if self.read_type == "valid" {
+            // polyA and quality trimming may leave nothing, or an insert below --min-insert
+            let out_len = cutright - cutleft;
+            let trimmed_insert = patternargs.trim_n == 0 && !patternargs.softmask;
+            if out_len == 0 {
+                self.read_type = "filtered_qtrim".to_string();
+            } else if trimmed_insert && patternargs.min_insert > 0 && out_len < patternargs.min_insert {
+                self.read_type = "filtered_insert".to_string();
             }
-
-            self.out_record= Record::with_attrs(&format!("{}{}{}{}{}", self.record.id(),id_sep,self.strand_orient,id_sep,self.record_id), None, &self.record.seq()[cutleft..cutright], &self.record.qual()[cutleft..cutright]);
+            if self.read_type != "valid" {
+                self.write_to_fq = false;
+                if !patternargs.stdout {
+                    return;
+                }
+            }
+        }
+        self.build_out_record(patternargs, cutleft, cutright);
+    }
+    pub fn set_fusion(&mut self, patternargs: &PatternArgs) {
+        // a fusion read loses its assignment, the stdout record is rebuilt untrimmed with the new read type
+        self.read_type = "fusion".to_string();
+        self.write_to_fq = false;
+        if patternargs.stdout {
+            self.build_out_record(patternargs, 0, self.read_len);
+        }
+    }
+    fn build_out_record(&mut self, patternargs: &PatternArgs, cutleft: usize, cutright: usize) {
+        self.out_window = (cutleft, cutright);
+        let id = if patternargs.stdout || patternargs.keep_id {
+            self.record.id().to_string()
+        } else {
+            format!("{}{}{}{}{}", self.record.id(), patternargs.id_sep, self.strand_orient, patternargs.id_sep, self.record_id)
+        };
+        let desc = self.header_desc(patternargs);
+        let mut seq = if patternargs.softmask {
+            self.softmask_seq()[cutleft..cutright].to_vec()
+        } else {
+            self.record.seq()[cutleft..cutright].to_vec()
+        };
+        let mut qual = self.record.qual()[cutleft..cutright].to_vec();
+        if patternargs.orient && self.strand_orient == "rs" {
+            seq = reverse_complement_bytes(&seq);
+            qual.reverse();
+        }
+        self.out_record = Record::with_attrs(&id, desc.as_deref(), &seq, &qual);
+    }
+    fn softmask_seq(&self) -> Vec<u8> {
+        let mut seq = self.record.seq().to_vec();
+        for split_type in self.split_type_vec.iter() {
+            for matcher in [&split_type.left_matcher, &split_type.right_matcher] {
+                if matcher.status {
+                    seq[matcher.ystart..matcher.yend].make_ascii_lowercase();
+                }
+            }
+        }
+        seq
+    }
+    pub fn header_tags(&self, patternargs: &PatternArgs) -> Vec<(&'static str, &'static str, String)> {
+        // (SAM tag, key of key=value, value), BC keeps the same value as the record id
+        let mut tags = vec![
+            ("BC", "assign", self.record_id.clone()),
+            ("XR", "read_type", self.read_type.clone()),
+            ("XS", "strand", self.strand_orient.clone()),
+            ("XN", "match_names", self.match_names.join(",")),
+            ("XT", "match_types", self.match_types.join(",")),
+        ];
+        let (cutleft, cutright) = self.out_window;
+        if patternargs.polya {
+            tags.push(("YA", "polya", self.polya_info()));
+        }
+        if patternargs.trim_coord {
+            // 0-based half-open coordinates of the output on the original read
+            tags.push(("XC", "trim", format!("{}-{}", cutleft, cutright)));
+        }
+        if patternargs.trim_seqs {
+            let seq = String::from_utf8_lossy(self.record.seq());
+            let qual = String::from_utf8_lossy(self.record.qual());
+            tags.push(("B1", "trim_left_seq", seq[..cutleft].to_string()));
+            tags.push(("Y1", "trim_left_qual", qual[..cutleft].to_string()));
+            tags.push(("B2", "trim_right_seq", seq[cutright..].to_string()));
+            tags.push(("Y2", "trim_right_qual", qual[cutright..].to_string()));
+        }
+        tags
+    }
+    fn header_desc(&mut self, patternargs: &PatternArgs) -> Option<String> {
+        // keep the original description, append the assignment as <sam> tags or <kv> pairs
+        let raw_desc = self.record.desc().map(|desc| desc.to_string());
+        let tags = self.header_tags(patternargs);
+        // the original description moves to a CO tag so SAM tags stay valid for minimap2 -y and uBAM
+        self.out_tags = tags.iter().map(|(tag, _, value)| (*tag, value.clone())).collect();
+        if let Some(desc) = &raw_desc {
+            self.out_tags.insert(0, ("CO", desc.clone()));
+        }
+        match patternargs.tag_format.as_str() {
+            "sam" => {
+                let fields: Vec<String> = self.out_tags.iter().map(|(tag, value)| format!("{}:Z:{}", tag, value)).collect();
+                Some(fields.join("\t"))
+            }
+            "kv" => {
+                let mut fields: Vec<String> = raw_desc.into_iter().collect();
+                fields.extend(tags.iter().map(|(_, key, value)| format!("{}={}", key, value)));
+                Some(fields.join(" "))
+            }
+            _ => raw_desc,
+        }
+    }
+    fn polya_info(&self) -> String {
+        match self.polya {
+            Some((base, start, end)) => format!("poly{}:{}", base as char, end - start),
+            None => "none".to_string(),
         }
     }
     pub fn to_tsv(&self) -> String {
         let mut split_type_info =
-            String::from(format!("{}\t{}\t{}", self.record.id(), self.read_len, self.read_type));
+            format!("{}\t{}\t{}", self.record.id(), self.read_len, self.read_type);
         for split_type in self.split_type_vec.iter() {
             split_type_info += format!("\t{}", split_type.to_info(),).as_str();
         }
-        return split_type_info;
+        // fixed trailing columns keep the log parsable by position
+        split_type_info += format!("\t{}\t{}", self.polya_info(), if self.rescued { "rescued" } else { "-" }).as_str();
+        split_type_info
     }
     // pub fn filter_read
 }
+
+#[test]
+pub fn test_skip_bad_reads(){
+    let file = std::env::temp_dir().join(format!("jasper_bad_{}.fq", std::process::id()));
+    // the second record has a quality string shorter than its sequence
+    std::fs::write(&file, "@r1\nACGT\n+\nIIII\n@r2\nACGT\n+\nII\n@r3\nACGT\n+\nIIII\n").unwrap();
+    let files = vec![file.display().to_string()];
+    let (rrx, handle) = spawn_reader(files.clone(), true);
+    assert_eq!(rrx.iter().count(), 2);
+    assert_eq!(handle.join().unwrap().unwrap(), 1);
+    let (rrx, handle) = spawn_reader(files, false);
+    assert_eq!(rrx.iter().count(), 1);
+    let error = handle.join().unwrap().unwrap_err();
+    assert!(matches!(error, JasperError::Fastq { record: 2, .. }));
+    assert_eq!(error.exit_code(), 7);
+    std::fs::remove_file(&file).unwrap();
+}
+
+#[test]
+pub fn test_fusion_tags(){
+    use clap::Parser;
+    let args = crate::args::Args::parse_from(["jasper", "-p", "example/primer.list", "-d", "example/pattern.db", "--stdout"]);
+    let patternargs = PatternArgs::new(&args);
+    let mut readinfo = ReadInfo::new(Record::with_attrs("r1", None, b"ACGTACGTAC", b"IIIIIIIIII"));
+    readinfo.read_type = "valid".to_string();
+    readinfo.write_to_fq = true;
+    readinfo.out_record = Record::with_attrs("r1", Some("XR:Z:valid"), b"GTAC", b"IIII");
+    readinfo.set_fusion(&patternargs);
+    // the tags and the untrimmed record follow the fusion read type
+    assert!(!readinfo.write_to_fq);
+    assert!(readinfo.out_tags.contains(&("XR", "fusion".to_string())));
+    assert!(readinfo.out_record.desc().unwrap().contains("XR:Z:fusion"));
+    assert_eq!(readinfo.out_record.seq(), b"ACGTACGTAC");
+}
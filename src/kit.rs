@@ -0,0 +1,66 @@
+use crate::error::{JasperError, Result};
+
+/// A barcode kit built into jasper: a stamped pattern db and the pattern list of its level.
+#[derive(Debug)]
+pub struct Kit {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub db: &'static str,
+    pub list: &'static str,
+}
+
+// ONT native, rapid and PCR kits share one barcode set, named by its native barcodes
+const ONT_BARCODE_DB: &str = include_str!("../kits/ont_barcode.db");
+
+pub const KITS: &[Kit] = &[
+    Kit {
+        name: "SQK-NBD114-24",
+        description: "ONT native barcoding kit 24 V14, NB01-NB24",
+        db: ONT_BARCODE_DB,
+        list: include_str!("../kits/SQK-NBD114-24.list"),
+    },
+    Kit {
+        name: "EXP-NBD104",
+        description: "ONT native barcoding expansion 1-12, NB01-NB12",
+        db: ONT_BARCODE_DB,
+        list: include_str!("../kits/EXP-NBD104.list"),
+    },
+    Kit {
+        name: "EXP-NBD114",
+        description: "ONT native barcoding expansion 13-24, NB13-NB24",
+        db: ONT_BARCODE_DB,
+        list: include_str!("../kits/EXP-NBD114.list"),
+    },
+    Kit {
+        name: "SQK-RBK114-24",
+        description: "ONT rapid barcoding kit 24 V14, RB01-RB24 on the 5' end",
+        db: ONT_BARCODE_DB,
+        list: include_str!("../kits/SQK-RBK114-24.list"),
+    },
+    Kit {
+        name: "EXP-PBC001",
+        description: "ONT PCR barcoding expansion 1-12, BC01-BC12",
+        db: ONT_BARCODE_DB,
+        list: include_str!("../kits/EXP-PBC001.list"),
+    },
+];
+
+/// Look up kits by name, in the order given.
+pub fn find_kits(names: &[String]) -> Result<Vec<&'static Kit>> {
+    names
+        .iter()
+        .map(|name| {
+            KITS.iter()
+                .find(|kit| kit.name == name)
+                .ok_or_else(|| JasperError::Config(format!("no built-in kit ({}), see `jasper kits`", name)))
+        })
+        .collect()
+}
+
+/// Print the name and description of every built-in kit.
+pub fn list_kits() {
+    println!("kit\tdescription");
+    for kit in KITS {
+        println!("{}\t{}", kit.name, kit.description);
+    }
+}
@@ -1,13 +1,19 @@
 mod args;
+mod bam;
 mod pattern;
 mod utils;
 mod counter;
+mod crypt;
+mod discover;
+mod error;
 mod fastq;
+mod kit;
 mod myers;
 mod splitter;
+mod validate;
 mod writer;
 use clap::Parser;
-use log::{info,debug};
+use log::{info,debug,error};
 use utils::ProcessInfo;
 
 fn main() {
@@ -16,27 +22,68 @@ fn main() {
     let comands: Vec<String> = std::env::args().collect();
     info!("Run Command: {:?}", comands);
     let args = args::Args::parse();
+    if let Err(e) = run(args) {
+        error!("{}", e);
+        std::process::exit(e.exit_code());
+    }
+}
+
+fn run(args: args::Args) -> error::Result<()> {
     if let Some(command) = args.command {
         match command {
-            args::Commands::Encrypt { file } => {
-                pattern::encrypt_pattern_db(&file, "666666");
-                return;
+            args::Commands::Encrypt { file, recipients, recipients_files, passphrase_file } => {
+                return crypt::encrypt_file(&file, &recipients, &recipients_files, passphrase_file);
+            }
+            args::Commands::Validate { args } => {
+                let args = args::Args::parse_from(std::iter::once("jasper".to_string()).chain(args));
+                let collisions = validate::check_collisions(&pattern::get_patterns(&args)?);
+                println!("level\tpattern1\tpattern2\tstrand\tdistance\tmax_dist\tseverity");
+                for collision in &collisions {
+                    println!("{}", collision.to_tsv());
+                }
+                return validate::log_collisions(&collisions, true);
+            }
+            args::Commands::Decrypt { file, output, key } => {
+                let key_source = crypt::KeySource::new(key.passphrase_file, key.identity_files);
+                return crypt::decrypt_file(&file, output, &key_source);
+            }
+            args::Commands::Kits => {
+                kit::list_kits();
+                return Ok(());
+            }
+            args::Commands::Db { command: args::DbCommands::Info { file, key } } => {
+                let key_source = crypt::KeySource::new(key.passphrase_file, key.identity_files);
+                return pattern::db_info(&file, &key_source);
+            }
+            args::Commands::Db { command: args::DbCommands::Stamp { file, kit, version, date } } => {
+                return pattern::db_stamp(&file, kit, version, date);
+            }
+            args::Commands::Db { command: args::DbCommands::Rekey { file, key, recipients, recipients_files, new_passphrase_file } } => {
+                let key_source = crypt::KeySource::new(key.passphrase_file, key.identity_files);
+                return crypt::rekey_file(&file, &key_source, &recipients, &recipients_files, new_passphrase_file);
             }
             // Handle other subcommands if any
         }
     }
     // debug!("{:?}", args);
-    let search_patterns = pattern::get_patterns(&args);
+    let mut search_patterns = pattern::get_patterns(&args)?;
+    // the merged db is only needed by the discover report, keep it out of the splitter threads
+    let pattern_db = std::mem::take(&mut search_patterns.pattern_db);
+    let validated = validate::log_collisions(&validate::check_collisions(&search_patterns), false);
+    if !args.skip_validate {
+        validated?;
+    }
     // debug!("{:?}", search_patterns);
     let start_time = std::time::Instant::now();
     // info!("Create fq.gz reader handler");
-    let rrx: flume::Receiver<fastq::ReadInfo> = fastq::spawn_reader(args.inputs);
+    let (rrx, reader_handle) = fastq::spawn_reader(args.inputs, args.skip_bad_reads);
     // info!("Create fq.gz spliter handler");
     let srx = splitter::splitter_receiver(rrx, &search_patterns, args.threads);
     let mut counter_manager = counter::CounterManager::new(args.outdir.clone());
-    let mut writer_manager = writer::WriterManager::new(args.outdir.clone());
+    let mut writer_manager = writer::WriterManager::new(args.outdir.clone(), args.stdout.clone());
+    let mut discover_manager = args.discover.then(|| discover::DiscoverManager::new(args.outdir.clone(), &search_patterns, pattern_db, args.discover_reads, args.threads));
     // let mut readsinfo = HashMap::new();
-    let mut processinfo = ProcessInfo::new(args.log_num.clone());
+    let mut processinfo = ProcessInfo::new(args.log_num);
 
     for readinfo in srx {
         //将readinfo.tsv()写入文件ARG.output, 需要使用GzEncoder写出为gz文件
@@ -45,14 +92,28 @@ fn main() {
         writer_manager.logger.push(readinfo.to_tsv());
         // info!("read to_name: {:?}", readinfo.read_names);
         counter_manager.counter_read(&readinfo);
+        if let Some(discover_manager) = discover_manager.as_mut() {
+            discover_manager.add(&readinfo);
+        }
         writer_manager.write(readinfo).expect("writing readinfo fail");
         processinfo.info();
     }
+    let read_result = reader_handle.join().expect("reader thread panicked");
     // splitter::splitter_logger(&readinfo, &mut logger);
     writer_manager.write_log_file(&args.outdir).expect("writer read_log fail");
-    counter_manager.write_total_info();
+    if read_result.is_err() {
+        // close the fq.gz files written so far before stopping on a bad record
+        writer_manager.drop();
+    }
+    counter_manager.skipped_reads = read_result?;
+    counter_manager.write_total_info(&search_patterns.db_meta);
     // counter_manager.write_detailed_info();
     counter_manager.write_valid_info();
+    counter_manager.write_qc_info();
+    counter_manager.write_filter_info();
+    if let Some(discover_manager) = &discover_manager {
+        discover_manager.write_discover_info()?;
+    }
     debug!("counter_manager: {:?}", counter_manager.counter);
     let mut elapsed_time = start_time.elapsed();
     counter_manager.info();
@@ -60,4 +121,5 @@ fn main() {
     writer_manager.drop();
     elapsed_time = start_time.elapsed();
     info!("All done! Total time elapsed: {:.4?}", elapsed_time);
+    Ok(())
 }
@@ -27,6 +27,7 @@ pub struct SearchPattern{
     pub pattern: Vec<u8>, // search pattern
     pub dist_ratio: f32, // error ratio
     pub max_dist: u8, // max distance, already trimN
+    pub min_overlap: usize, // min overlap of end-anchored match, 0 means disabled
     pub start: usize,
     pub end: usize,
 }
@@ -37,8 +38,9 @@ impl SearchPattern {
             text: Vec::new(),
             raw_text_len: raw_text.len(),
             pattern: Vec::new(),
-            dist_ratio: dist_ratio,
+            dist_ratio,
             max_dist: 0,
+            min_overlap: 0,
             start: 0,
             end: 0,
             
@@ -68,3 +70,61 @@ pub fn myers_best(input:&SearchPattern) -> Option<(i32, usize, usize,)>{
         None => None,
     }
 }
+
+pub fn myers_end_anchored(input: &SearchPattern, anchor: &str) -> Option<(i32, usize, usize)> {
+    // semi-global alignment of a pattern cut by the read boundary,
+    // <left> a pattern suffix starts at the first text base, <right> a pattern prefix ends at the last text base
+    let (pattern, text): (Vec<u8>, Vec<u8>) = match anchor {
+        "left" => (input.pattern.clone(), input.text.clone()),
+        "right" => (input.pattern.iter().rev().copied().collect(), input.text.iter().rev().copied().collect()),
+        _ => return None,
+    };
+    let m = pattern.len();
+    let n = text.len().min(2 * m);
+    // cost and pattern start row of each cell, any pattern prefix can be skipped for free
+    let mut prev: Vec<(usize, usize)> = (0..=n).map(|j| (j, 0)).collect();
+    let mut best: Option<(usize, usize, usize)> = None; // (cost, overlap, text end)
+    for i in 1..=m {
+        let mut cur = vec![(0, i); n + 1];
+        for j in 1..=n {
+            let matched = pattern[i - 1] == b'N' || pattern[i - 1].eq_ignore_ascii_case(&text[j - 1]);
+            let diag = (prev[j - 1].0 + usize::from(!matched), prev[j - 1].1);
+            let up = (prev[j].0 + 1, prev[j].1);
+            let left = (cur[j - 1].0 + 1, cur[j - 1].1);
+            cur[j] = [diag, up, left].into_iter().min_by_key(|(cost, start)| (*cost, *start)).unwrap();
+        }
+        prev = cur;
+    }
+    for (j, (cost, start)) in prev.iter().enumerate().skip(1) {
+        let overlap = m - start;
+        let max_dist = (overlap as f32 * input.dist_ratio).floor() as usize;
+        if overlap < input.min_overlap || *cost > max_dist {
+            continue;
+        }
+        if best.is_none_or(|(best_cost, best_overlap, _)| (*cost, std::cmp::Reverse(overlap)) < (best_cost, std::cmp::Reverse(best_overlap))) {
+            best = Some((*cost, overlap, j));
+        }
+    }
+    let (cost, _, j) = best?;
+    match anchor {
+        "left" => Some((cost as i32, input.start, input.start + j)),
+        _ => Some((cost as i32, input.end - j, input.end)),
+    }
+}
+
+#[test]
+pub fn test_myers_end_anchored(){
+    let pattern = b"AAGACCACAAGGCCGATGATTAAGG".to_vec();
+    // read starts with the last 15 bases of the pattern
+    let mut search_pattern = SearchPattern::new(b"GGCCGATGATTAAGGTTTTCCCCGGGG".to_vec(), 0.2);
+    search_pattern.update(0, 27, pattern.clone());
+    search_pattern.min_overlap = 10;
+    assert_eq!(myers_end_anchored(&search_pattern, "left"), Some((0, 0, 15)));
+    search_pattern.min_overlap = 16;
+    assert_eq!(myers_end_anchored(&search_pattern, "left"), None);
+    // read ends with the first 12 bases of the pattern, one mismatch
+    let mut search_pattern = SearchPattern::new(b"TTTTCCCCGGGGAAGACCTCAAGG".to_vec(), 0.2);
+    search_pattern.update(0, 24, pattern);
+    search_pattern.min_overlap = 10;
+    assert_eq!(myers_end_anchored(&search_pattern, "right"), Some((1, 12, 24)));
+}
@@ -1,15 +1,19 @@
-use csv;
-use log::info;
+use log::{info, warn};
 use std::collections::HashMap;
 use crate::args::Args;
 use crate::utils::reverse_complement;
-use age::secrecy::SecretString;
-use std::fs::File;
-use std::io::{Read, Write};
+use crate::crypt::KeySource;
+use crate::error::{JasperError, Result};
+use crate::kit::{find_kits, Kit};
+use flate2::read::MultiGzDecoder;
+use sha2::{Digest, Sha256};
+use std::io::Read;
 
 #[derive(Debug,Clone)]
 pub struct PatternArgs {
     pub window_size: Vec<usize>,
+    pub window_adaptive: bool,
+    pub level_window: Vec<(usize, usize)>,
     pub pattern_match: Vec<String>,
     pub pattern_vec: Vec<PatternArg>,
     pub trim_n: usize,
@@ -18,14 +22,41 @@ pub struct PatternArgs {
     pub pattern_maxdist: Vec<usize>,
     pub pattern_shift: Vec<usize>,
     pub min_length: usize,
+    pub max_length: usize,
+    pub min_qual: f64,
+    pub max_n: f64,
+    pub max_homopolymer: usize,
+    pub min_insert: usize,
+    pub max_insert: usize,
+    pub dimer_len: usize,
+    pub qtrim: String,
+    pub qtrim_q: u8,
+    pub qtrim_window: usize,
+    pub polya: bool,
+    pub polya_min: usize,
+    pub polya_window: usize,
+    pub rescue: bool,
+    pub rescue_errate: f32,
     pub id_sep: String,
     pub fusion_db: FusionDB,
     pub fusion_errate: f32,
+    pub stdout: bool,
+    pub keep_id: bool,
+    pub tag_format: String,
+    pub trim_coord: bool,
+    pub trim_seqs: bool,
+    pub softmask: bool,
+    pub orient: bool,
+    pub key_source: KeySource,
+    pub db_meta: Vec<DbMeta>, // header of each pattern db
+    pub pattern_db: HashMap<String, String>, // merged entries of every pattern db, used by the discover report
 }
 impl PatternArgs {
     pub fn new(inputargs: &Args) -> PatternArgs {
         let mut p = PatternArgs {
             window_size: inputargs.window_size.clone(),
+            window_adaptive: inputargs.window_adaptive,
+            level_window: inputargs.level_window.clone(),
             pattern_match: inputargs.pattern_match.clone(),
             pattern_vec: vec![],
             trim_n: inputargs.trim_n,
@@ -34,18 +65,68 @@ impl PatternArgs {
             pattern_maxdist: inputargs.pattern_maxdist.clone(),
             pattern_shift: inputargs.pattern_shift.clone(),
             min_length: inputargs.min_length,
+            max_length: inputargs.max_length,
+            min_qual: inputargs.min_qual,
+            max_n: inputargs.max_n,
+            max_homopolymer: inputargs.max_homopolymer,
+            min_insert: inputargs.min_insert,
+            max_insert: inputargs.max_insert,
+            dimer_len: inputargs.dimer_len,
+            qtrim: inputargs.qtrim.clone(),
+            qtrim_q: inputargs.qtrim_q,
+            qtrim_window: inputargs.qtrim_window,
+            polya: inputargs.polya,
+            polya_min: inputargs.polya_min,
+            polya_window: inputargs.polya_window,
+            rescue: inputargs.rescue,
+            rescue_errate: inputargs.rescue_errate,
             id_sep: inputargs.id_sep.clone(),
             fusion_db: FusionDB::new(),
             fusion_errate: inputargs.fusion_errate,
+            stdout: inputargs.stdout.is_some(),
+            keep_id: inputargs.keep_id,
+            tag_format: PatternArgs::resolve_tag_format(inputargs),
+            trim_coord: inputargs.trim_coord,
+            trim_seqs: inputargs.trim_seqs,
+            softmask: inputargs.softmask,
+            orient: inputargs.orient,
+            key_source: KeySource::new(inputargs.passphrase_file.clone(), inputargs.identity_files.clone()),
+            db_meta: Vec::new(),
+            pattern_db: HashMap::new(),
         };
         p.fix_vec();
-        return p;
+        p
     }
     pub fn fix_vec(&mut self) {
         PatternArgs::resize_to_min_length(&mut self.pattern_match, 5);
         PatternArgs::resize_to_min_length(&mut self.pattern_errate, 5);
         PatternArgs::resize_to_min_length(&mut self.pattern_maxdist, 5);
         PatternArgs::resize_to_min_length(&mut self.pattern_shift, 5);
+        // levels without their own --level-window search in --window-size
+        while self.level_window.len() < 5 {
+            self.level_window.push((self.window_size[0], self.window_size[1]));
+        }
+    }
+    pub fn rescue_args(&self) -> PatternArgs {
+        // relaxed copy for unknown reads: higher errate, whole read windows, single-end acceptance
+        let mut rescue_args = self.clone();
+        rescue_args.window_adaptive = false;
+        rescue_args.pattern_match = vec!["single".to_string(); self.pattern_match.len()];
+        for patternarg in rescue_args.pattern_vec.iter_mut() {
+            patternarg.pattern_errate.0 = patternarg.pattern_errate.0.max(self.rescue_errate);
+            patternarg.pattern_errate.1 = patternarg.pattern_errate.1.max(self.rescue_errate);
+            patternarg.window_size = (usize::MAX, usize::MAX);
+        }
+        rescue_args
+    }
+    fn resolve_tag_format(inputargs: &Args) -> String {
+        // stdout stream defaults to SAM tags, an untouched read id or trim info needs tags in the header
+        match &inputargs.tag_format {
+            Some(tag_format) => tag_format.clone(),
+            None if inputargs.stdout.is_some() => "sam".to_string(),
+            None if inputargs.keep_id || inputargs.trim_coord || inputargs.trim_seqs => "kv".to_string(),
+            None => "none".to_string(),
+        }
     }
     fn resize_to_min_length<T: Clone + Default>(vec: &mut Vec<T>, min_length: usize) {
         if vec.len() < min_length {
@@ -62,35 +143,30 @@ pub struct PatternArg {
     // pub pattern_shift: usize,       // >0 means shift to right, <0 means shift to left
     pub pattern_errate: (f32, f32), // error rate for left and right
     pub pattern_maxdist: usize,     // max distance in matcher for left and right
-    pub pattern_shift: usize
+    pub pattern_shift: usize,
+    pub min_overlap: usize,         // min overlap of end-anchored partial match, 0 means disabled
+    pub window_size: (usize, usize), // window to find pattern on left and right
 }
 
-pub fn encrypt_pattern_db(file: &str, passphrase: &str) {
-    // Read the file content
-    let mut f = File::open(file).expect(&format!("no such file ({}) found", file));
-    let mut content = Vec::new();
-    f.read_to_end(&mut content).unwrap();
-
-    // Encrypt the content
-    let passphrase = SecretString::from(passphrase.to_owned());
-    let recipient = age::scrypt::Recipient::new(passphrase.clone());
-    let encrypted_data = age::encrypt(&recipient, &content).unwrap();
-
-    // Write the encrypted content to a new file with .age suffix
-    let output_file = format!("{}.safe", file);
-    let mut out = File::create(&output_file).unwrap();
-    out.write_all(&encrypted_data).unwrap();
-    info!("Encrypted pattern db file saved to {}", output_file);
+#[derive(Debug,Clone)]
+pub struct PatternType {
+    pub pattern_name: String,               // pattern pair name ex:4.2-F_3.7-R, RB01_ for a 5' only row
+    pub pattern_type: String,               // pattern type ex:alpha
+    pub pattern_strand: String,             // fs, rs or unknown
+    pub insert_range: Option<(usize, usize)>, // expected insert length between the pair
+    pub strand_restrict: Option<String>,    // only accept the pair in this orientation, fs or rs
+    pub alias: Option<String>,              // sample alias used as output name instead of the type
 }
 
-
-
 #[derive(Debug,Clone)]
 pub struct PatternDB {
     // patterns to find
     pub f_patterns: HashMap<String, String>,
     pub r_patterns: HashMap<String, String>,
-    pub pattern_type: HashMap<String,(String, String, String)>,
+    pub pattern_type: HashMap<String, PatternType>,
+    // per pattern search tolerance from the list, replaces the level errate and len * errate
+    pub pattern_errate: HashMap<String, (f32, f32)>,
+    pub pattern_max_dist: HashMap<String, u8>,
 }
 
 impl PatternDB {
@@ -99,164 +175,538 @@ impl PatternDB {
             f_patterns: HashMap::new(),
             r_patterns: HashMap::new(),
             pattern_type: HashMap::new(),
+            pattern_errate: HashMap::new(),
+            pattern_max_dist: HashMap::new(),
         }
     }
-    pub fn get_pattern(&mut self, pattern_db_file: &String, pattern_file: &String){
-        let pattern_db = self.loading_pattern_db(pattern_db_file,"666666");
+    pub fn get_pattern(&mut self, db_file: &str, pattern_db: &HashMap<String, String>, pattern_file: &str, len_tolerance: f32, key_source: &KeySource) -> Result<()> {
+        let list = read_list(pattern_file, key_source)?;
         self.loading_pattern(
             pattern_file,
+            list,
+            db_file,
             pattern_db,
-        );
-    }
-    fn loading_pattern_db(&self, file: &str, passphrase: &str) -> HashMap<String, String> {
-        let mut pattern_db = HashMap::new();
-        let mut content = Vec::new();
-
-        if file.ends_with(".safe") {
-            // Decrypt the file
-            let passphrase = SecretString::from(passphrase.to_owned());
-            let identity = age::scrypt::Identity::new(passphrase);
-            let mut encrypted_file = File::open(file).expect(&format!("no such file({}) found", file));
-            encrypted_file.read_to_end(&mut content).unwrap();
-            let decrypted_data = age::decrypt(&identity, &content[..]).unwrap();
-            content = decrypted_data;
-        } else {
-            // Read the file directly
-            let mut f = File::open(file).expect(&format!("no such file({}) found", file));
-            f.read_to_end(&mut content).unwrap();
+            len_tolerance,
+        )
+    }
+    pub fn get_kit_pattern(&mut self, kit: &Kit, db_file: &str, pattern_db: &HashMap<String, String>, len_tolerance: f32) -> Result<()> {
+        let file = format!("kit {}", kit.name);
+        let list = parse_list(kit.list);
+        self.loading_pattern(&file, list, db_file, pattern_db, len_tolerance)
+    }
+    pub fn get_kits_pattern(&mut self, kits: &[&Kit], db_file: &str, pattern_db: &HashMap<String, String>, len_tolerance: f32) -> Result<()> {
+        // kits given together are alternatives on the same read, they share one level
+        let mut owner: HashMap<String, &str> = HashMap::new();
+        for kit in kits {
+            let mut kitdb = PatternDB::new();
+            kitdb.get_kit_pattern(kit, db_file, pattern_db, len_tolerance)?;
+            for (key, pattern_type) in kitdb.pattern_type {
+                if let Some(old) = self.pattern_type.get(&key).filter(|old| old.pattern_type != pattern_type.pattern_type) {
+                    return Err(JasperError::Config(format!(
+                        "kits ({}) and ({}) name the pattern pair ({}) differently: {} and {}",
+                        owner[&key], kit.name, key, old.pattern_type, pattern_type.pattern_type
+                    )));
+                }
+                owner.insert(key.clone(), kit.name);
+                self.pattern_type.insert(key, pattern_type);
+            }
+            self.f_patterns.extend(kitdb.f_patterns);
+            self.r_patterns.extend(kitdb.r_patterns);
+            self.pattern_errate.extend(kitdb.pattern_errate);
+            self.pattern_max_dist.extend(kitdb.pattern_max_dist);
         }
-
-        let cursor = std::io::Cursor::new(content);
-        let mut rdr = csv::ReaderBuilder::new()
-            .has_headers(false)
-            .delimiter(b'\t')
-            .from_reader(cursor);
-
-        for result in rdr.records() {
-            let recored = result.unwrap();
-            let name = &recored[0];
-            let seq = &recored[1];
-            pattern_db.insert(name.to_string(), seq.to_string());
+        Ok(())
+    }
+    fn loading_pattern(&mut self, file: &str, list: (Vec<String>, ListRows), db_file: &str, pattern_db: &HashMap<String, String>, len_tolerance: f32) -> Result<()> {
+        //loading tsv file, F, R and name come first, then optional named columns:
+        // expected_len (N or MIN-MAX), min_len, max_len, errate (E or L,R), max_dist, strand (fs, rs or both), alias
+        let (mut headers, mut rows) = list;
+        if headers.is_empty() {
+            // a header without `#` and known columns: its cells are column names and no db entries,
+            // a first row with unknown pattern names stays a row and fails like any other
+            let header_cell = |cell: &String| column_like(cell) && !pattern_db.contains_key(cell);
+            if rows.first().is_some_and(|(_, fields)| fields.len() > 1 && fields.iter().take(3).all(header_cell)) {
+                headers = rows.remove(0).1;
+                info!("first row of ({}) is taken as header: {}", file, headers.join(", "));
+            }
+        }
+        for header in headers.iter().skip(3).filter(|header| !LIST_COLUMNS.contains(&header.as_str())) {
+            warn!("unknown column ({}) of ({}) is ignored, known columns: {}", header, file, LIST_COLUMNS.join(", "));
         }
-        return pattern_db;
-    }
-    fn loading_pattern(&mut self, file: &str, pattern_db: HashMap<String, String>){
-        //loading tsv file
-        // let file = File::open(file).unwrap();
-        let mut rdr = csv::ReaderBuilder::new()
-            .has_headers(true)
-            .delimiter(b'\t')
-            .from_path(file)
-            .expect(&format!("no such file({}) found", file));
-        for result in rdr.records() {
-            let record = result.unwrap();
-            let (f, r, name) = (record[0].to_string(), record[1].to_string(), record[2].to_string());
+        let column = |name: &str| headers.iter().position(|header| header == name);
+        let (expected_col, min_col, max_col) = (column("expected_len"), column("min_len"), column("max_len"));
+        let (errate_col, max_dist_col, strand_col, alias_col) = (column("errate"), column("max_dist"), column("strand"), column("alias"));
+        for (line, record) in rows {
+            if record.len() < 3 {
+                return Err(JasperError::parse(file, line, "expected F, R and name columns"));
+            }
+            // `-` or an empty cell: the row has a 5' or a 3' pattern only
+            let side = |value: &str| if value == "-" { String::new() } else { value.to_string() };
+            let (f, r, name) = (side(&record[0]), side(&record[1]), record[2].clone());
+            if f.is_empty() && r.is_empty() {
+                return Err(JasperError::parse(file, line, "expected an F or R pattern"));
+            }
+            let cell = |col: Option<usize>| col.and_then(|i| record.get(i)).map(|value| value.trim()).filter(|value| !value.is_empty());
+            let invalid = |column: &str, value: &str| JasperError::parse(file, line, format!("invalid {} ({}) for pattern ({}_{})", column, value, f, r));
+            let insert_range = parse_insert_range(cell(expected_col), cell(min_col), cell(max_col), len_tolerance)
+                .map_err(|err| JasperError::parse(file, line, format!("{} for pattern ({}_{})", err, f, r)))?;
+            let errate = cell(errate_col)
+                .map(|value| parse_errate(value).ok_or_else(|| invalid("errate", value)))
+                .transpose()?;
+            let max_dist = cell(max_dist_col)
+                .map(|value| value.parse::<u8>().map_err(|_| invalid("max_dist", value)))
+                .transpose()?;
+            let strand_restrict = match cell(strand_col) {
+                None | Some("both") => None,
+                Some(value @ ("fs" | "rs")) => Some(value.to_string()),
+                Some(value) => return Err(invalid("strand", value)),
+            };
+            let alias = cell(alias_col).map(str::to_string);
+            for pattern in [&f, &r].into_iter().filter(|pattern| !pattern.is_empty()) {
+                self.set_tolerance(pattern, errate, max_dist)
+                    .map_err(|msg| JasperError::parse(file, line, msg))?;
+            }
             let key_fs = format!("{}_{}",f,r);
             let key_rs = format!("{}_{}",r,f);
-            let f_seq = pattern_db
-                .get(&f)
-                .expect(&format!("no such pattern({}) in pattern_db", f))
-                .to_string();
-            let r_seq = pattern_db
-                .get(&r)
-                .expect(&format!("no such pattern({}) in pattern_db", r))
-                .to_string();
-            self.f_patterns.insert(f.clone(), f_seq.clone());
-            self.f_patterns.insert(r.clone(), r_seq.clone());
-            self.r_patterns.insert(f.clone(), reverse_complement(&f_seq));
-            self.r_patterns.insert(r.clone(), reverse_complement(&r_seq));
+            let lookup = |pattern: &str| {
+                pattern_db.get(pattern).cloned().ok_or_else(|| JasperError::MissingPattern {
+                    file: file.to_string(),
+                    line,
+                    name: pattern.to_string(),
+                    db: db_file.to_string(),
+                })
+            };
+            for pattern in [&f, &r].into_iter().filter(|pattern| !pattern.is_empty()) {
+                let seq = lookup(pattern)?;
+                self.r_patterns.insert(pattern.clone(), reverse_complement(&seq));
+                self.f_patterns.insert(pattern.clone(), seq);
+            }
+            let pattern_type = |strand: &str| PatternType {
+                pattern_name: key_fs.clone(),
+                pattern_type: name.clone(),
+                pattern_strand: strand.to_string(),
+                insert_range,
+                strand_restrict: strand_restrict.clone(),
+                alias: alias.clone(),
+            };
             if key_fs != key_rs {
-                self.pattern_type.insert(key_fs.clone(), (key_fs.clone(),name.clone(),"fs".to_string()));
-                self.pattern_type.insert(key_rs.clone(), (key_fs.clone(),name.clone(),"rs".to_string()));
+                self.pattern_type.insert(key_fs.clone(), pattern_type("fs"));
+                self.pattern_type.insert(key_rs.clone(), pattern_type("rs"));
             }else {
-                self.pattern_type.insert(key_fs.clone(), (key_fs.clone(),name.clone(),"unknown".to_string()));
+                self.pattern_type.insert(key_fs.clone(), pattern_type("unknown"));
             }
         }
         info!("loading pattern file success({})...", file);
+        Ok(())
+    }
+    fn set_tolerance(&mut self, pattern: &str, errate: Option<(f32, f32)>, max_dist: Option<u8>) -> std::result::Result<(), String> {
+        // a pattern shared by several rows must get the same tolerance from each of them
+        if let Some(errate) = errate {
+            if let Some(old) = self.pattern_errate.insert(pattern.to_string(), errate) {
+                if old != errate {
+                    return Err(format!("conflicting errate of pattern ({}): {:?} and {:?}", pattern, old, errate));
+                }
+            }
+        }
+        if let Some(max_dist) = max_dist {
+            if let Some(old) = self.pattern_max_dist.insert(pattern.to_string(), max_dist) {
+                if old != max_dist {
+                    return Err(format!("conflicting max_dist of pattern ({}): {} and {}", pattern, old, max_dist));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// data rows of a list file with their line numbers
+type ListRows = Vec<(u64, Vec<String>)>;
+
+// optional named columns of a pattern list after F, R and name
+const LIST_COLUMNS: [&str; 7] = ["expected_len", "min_len", "max_len", "errate", "max_dist", "strand", "alias"];
+
+/// A cell of a header row without `#`: a known column or a name like F, primary_F, index_R, type or fusion_pattern.
+fn column_like(cell: &str) -> bool {
+    let cell = cell.to_ascii_lowercase();
+    LIST_COLUMNS.contains(&cell.as_str())
+        || ["f", "r", "type", "name", "pattern"].contains(&cell.as_str())
+        || ["_f", "_r", "_type", "_name", "_pattern"].iter().any(|suffix| cell.ends_with(suffix))
+}
+
+/// Header and data rows of a list file. Blank lines and `#` comment lines are skipped. A `#` line
+/// before the first data row is the header when it names a known column, otherwise the first of them
+/// with tab separated fields is; a first data row naming a known column is the header as well.
+fn read_list(file: &str, key_source: &KeySource) -> Result<(Vec<String>, ListRows)> {
+    let content = read_config(file, key_source)?;
+    let content = String::from_utf8(content).map_err(|_| JasperError::parse(file, 0, "not a text file"))?;
+    Ok(parse_list(&content))
+}
+fn parse_list(content: &str) -> (Vec<String>, ListRows) {
+    let fields = |line: &str| line.split('\t').map(|field| field.trim().to_string()).collect::<Vec<String>>();
+    let named = |fields: &[String]| fields.iter().any(|field| LIST_COLUMNS.contains(&field.as_str()));
+    let mut comments: Vec<Vec<String>> = Vec::new();
+    let mut rows = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() {
+            continue;
+        }
+        if line.starts_with('#') {
+            if rows.is_empty() {
+                comments.push(fields(line.trim_start_matches('#')));
+            }
+        } else {
+            rows.push((i as u64 + 1, fields(line)));
+        }
+    }
+    let header = comments
+        .iter()
+        .rev()
+        .find(|fields| named(fields))
+        .or_else(|| comments.iter().find(|fields| fields.len() > 1))
+        .cloned();
+    match header {
+        Some(header) => (header, rows),
+        None if rows.first().is_some_and(|(_, fields)| named(fields)) => (rows.remove(0).1, rows),
+        None => (Vec::new(), rows),
+    }
+}
+
+fn parse_errate(value: &str) -> Option<(f32, f32)> {
+    let valid = |errate: f32| (0.0..=0.5).contains(&errate).then_some(errate);
+    match value.split_once(',') {
+        Some((left, right)) => Some((valid(left.trim().parse().ok()?)?, valid(right.trim().parse().ok()?)?)),
+        None => valid(value.parse().ok()?).map(|errate| (errate, errate)),
+    }
+}
+
+/// Content of a config file (db, pattern list or fusion list), `.safe` files are decrypted first.
+fn read_config(file: &str, key_source: &KeySource) -> Result<Vec<u8>> {
+    let content = std::fs::read(file).map_err(|e| JasperError::io(file, e))?;
+    if file.ends_with(".safe") {
+        return key_source.decrypt(file, &content);
+    }
+    Ok(content)
+}
+
+fn tsv_reader(content: Vec<u8>, has_headers: bool) -> csv::Reader<std::io::Cursor<Vec<u8>>> {
+    csv::ReaderBuilder::new()
+        .has_headers(has_headers)
+        .delimiter(b'\t')
+        .from_reader(std::io::Cursor::new(content))
+}
+
+fn record_line(record: &csv::StringRecord) -> u64 {
+    record.position().map_or(0, |position| position.line())
+}
+
+fn csv_error(file: &str, error: csv::Error, line_offset: u64) -> JasperError {
+    let line = error.position().map_or(0, |position| position.line()) + line_offset;
+    JasperError::parse(file, line, error.to_string())
+}
+
+/// Optional `##key=value` header of a pattern db: kit name, version, date and the declared checksum.
+#[derive(Debug, Clone, Default)]
+pub struct DbMeta {
+    pub kit: String,
+    pub version: String,
+    pub date: String,
+    pub checksum: Option<String>, // declared sha256 of the body
+    pub sha256: String,           // computed sha256 of the body
+}
+impl DbMeta {
+    /// Split the header off the db content, the body is every byte after the leading `##` lines.
+    pub fn parse(content: &[u8]) -> (DbMeta, &[u8]) {
+        let mut meta = DbMeta::default();
+        let mut body_start = 0;
+        while content[body_start..].starts_with(b"##") {
+            let line_end = content[body_start..].iter().position(|b| *b == b'\n').map_or(content.len(), |i| body_start + i + 1);
+            let line = String::from_utf8_lossy(&content[body_start + 2..line_end]);
+            if let Some((key, value)) = line.trim_end().split_once('=') {
+                let value = value.trim().to_string();
+                match key.trim() {
+                    "kit" => meta.kit = value,
+                    "version" => meta.version = value,
+                    "date" => meta.date = value,
+                    "checksum" => meta.checksum = Some(value.trim_start_matches("sha256:").to_lowercase()),
+                    _ => {}
+                }
+            }
+            body_start = line_end;
+        }
+        let body = &content[body_start..];
+        meta.sha256 = format!("{:x}", Sha256::digest(body));
+        (meta, body)
+    }
+    pub fn verified(&self) -> bool {
+        self.checksum.as_ref().is_some_and(|checksum| *checksum == self.sha256)
+    }
+    pub fn to_info(&self) -> String {
+        let show = |value: &str| if value.is_empty() { "NA".to_string() } else { value.to_string() };
+        format!("kit: {}, version: {}, date: {}, sha256: {}{}", show(&self.kit), show(&self.version), show(&self.date), self.sha256, if self.verified() { " (verified)" } else { "" })
+    }
+}
+
+/// Load every entry of a pattern db after checking the declared checksum of its header.
+pub fn read_pattern_db(file: &str, key_source: &KeySource) -> Result<(DbMeta, HashMap<String, String>)> {
+    parse_pattern_db(file, &read_db_content(file, key_source)?)
+}
+
+fn parse_pattern_db(file: &str, content: &[u8]) -> Result<(DbMeta, HashMap<String, String>)> {
+    let (meta, body) = DbMeta::parse(content);
+    if let Some(checksum) = &meta.checksum {
+        if *checksum != meta.sha256 {
+            return Err(JasperError::Checksum { file: file.to_string(), declared: checksum.clone(), computed: meta.sha256.clone() });
+        }
+    }
+    let header_lines = content[..content.len() - body.len()].iter().filter(|b| **b == b'\n').count() as u64;
+    let pattern_db = parse_db_entries(file, body, header_lines)?.into_iter().collect();
+    Ok((meta, pattern_db))
+}
+
+/// Load and merge several pattern dbs and the dbs of built-in kits, a name found in more than one
+/// db must have the same sequence in each.
+pub fn read_pattern_dbs(files: &[String], kits: &[&Kit], key_source: &KeySource) -> Result<(Vec<DbMeta>, HashMap<String, String>)> {
+    let mut metas = Vec::new();
+    let mut pattern_db: HashMap<String, String> = HashMap::new();
+    let mut origin: HashMap<String, &String> = HashMap::new();
+    let names = db_names(files, kits);
+    for (i, file) in names.iter().enumerate() {
+        let (meta, entries) = match files.get(i) {
+            Some(file) => read_pattern_db(file, key_source)?,
+            None => parse_pattern_db(file, kits[i - files.len()].db.as_bytes())?,
+        };
+        for (name, seq) in entries {
+            match pattern_db.get(&name) {
+                Some(old) if !old.eq_ignore_ascii_case(&seq) => {
+                    return Err(JasperError::DbConflict { file1: origin[&name].clone(), name, file2: file.clone() });
+                }
+                Some(_) => {}
+                None => {
+                    origin.insert(name.clone(), file);
+                    pattern_db.insert(name, seq);
+                }
+            }
+        }
+        metas.push(meta);
+    }
+    Ok((metas, pattern_db))
+}
+
+// name of each db in the order they are merged, kits after the files
+fn db_names(files: &[String], kits: &[&Kit]) -> Vec<String> {
+    files.iter().cloned().chain(kits.iter().map(|kit| format!("kit {}", kit.name))).collect()
+}
+
+/// Content of a pattern db, a gzipped db is decompressed after decryption.
+fn read_db_content(file: &str, key_source: &KeySource) -> Result<Vec<u8>> {
+    let content = read_config(file, key_source)?;
+    if !content.starts_with(&[0x1f, 0x8b]) {
+        return Ok(content);
+    }
+    let mut decompressed = Vec::new();
+    MultiGzDecoder::new(content.as_slice()).read_to_end(&mut decompressed).map_err(|e| JasperError::io(file, e))?;
+    Ok(decompressed)
+}
+
+/// Entries of a db body in file order, either tab separated name and sequence or FASTA.
+fn parse_db_entries(file: &str, body: &[u8], header_lines: u64) -> Result<Vec<(String, String)>> {
+    if body.trim_ascii_start().starts_with(b">") {
+        return parse_fasta(file, body, header_lines);
+    }
+    let mut entries = Vec::new();
+    let mut rdr = tsv_reader(body.to_vec(), false);
+    for result in rdr.records() {
+        let recored = result.map_err(|e| csv_error(file, e, header_lines))?;
+        let (Some(name), Some(seq)) = (recored.get(0), recored.get(1)) else {
+            return Err(JasperError::parse(file, record_line(&recored) + header_lines, "expected name and sequence columns"));
+        };
+        let seq = seq.trim();
+        check_seq(file, record_line(&recored) + header_lines, name, seq)?;
+        entries.push((name.to_string(), seq.to_string()));
+    }
+    Ok(entries)
+}
+
+fn check_seq(file: &str, line: u64, name: &str, seq: &str) -> Result<()> {
+    if seq.is_empty() {
+        return Err(JasperError::parse(file, line, format!("no sequence for ({})", name)));
+    }
+    match seq.chars().find(|base| !matches!(base.to_ascii_uppercase(), 'A' | 'C' | 'G' | 'T' | 'N')) {
+        Some(base) => Err(JasperError::parse(file, line, format!("invalid base ({:?}) in sequence of ({}), expected A, C, G, T or N", base, name))),
+        None => Ok(()),
+    }
+}
+
+fn parse_fasta(file: &str, body: &[u8], header_lines: u64) -> Result<Vec<(String, String)>> {
+    // the name is the first word of the `>` line, sequence lines may be wrapped
+    let body = std::str::from_utf8(body).map_err(|_| JasperError::parse(file, 0, "not a text file"))?;
+    let mut entries: Vec<(String, String)> = Vec::new();
+    for (i, line) in body.lines().enumerate() {
+        let line_number = i as u64 + 1 + header_lines;
+        let line = line.trim();
+        if let Some(name) = line.strip_prefix('>') {
+            let name = name.split_whitespace().next().ok_or_else(|| JasperError::parse(file, line_number, "empty fasta name"))?;
+            entries.push((name.to_string(), String::new()));
+        } else if let Some((name, seq)) = entries.last_mut() {
+            if !line.is_empty() {
+                check_seq(file, line_number, name, line)?;
+            }
+            seq.push_str(line);
+        } else if !line.is_empty() {
+            return Err(JasperError::parse(file, line_number, "expected a `>` name line"));
+        }
+    }
+    if let Some((name, _)) = entries.iter().find(|(_, seq)| seq.is_empty()) {
+        return Err(JasperError::parse(file, 0, format!("no sequence for ({})", name)));
+    }
+    Ok(entries)
+}
+
+/// Write the `##` header with the sha256 of the body into a plain pattern db, an existing header is replaced.
+pub fn db_stamp(file: &str, kit: Option<String>, version: Option<String>, date: Option<String>) -> Result<()> {
+    if file.ends_with(".safe") {
+        return Err(JasperError::Config(format!("stamp the plain pattern db before encrypting it, ({}) is encrypted", file)));
+    }
+    let content = std::fs::read(file).map_err(|e| JasperError::io(file, e))?;
+    if content.starts_with(&[0x1f, 0x8b]) {
+        return Err(JasperError::Config(format!("stamp the plain pattern db before compressing it, ({}) is gzipped", file)));
+    }
+    let (meta, body) = DbMeta::parse(&content);
+    let mut header = String::new();
+    for (key, value) in [("kit", kit.unwrap_or(meta.kit)), ("version", version.unwrap_or(meta.version)), ("date", date.unwrap_or(meta.date))] {
+        if !value.is_empty() {
+            header += &format!("##{}={}\n", key, value);
+        }
+    }
+    header += &format!("##checksum=sha256:{}\n", meta.sha256);
+    let mut stamped = header.into_bytes();
+    stamped.extend_from_slice(body);
+    std::fs::write(file, stamped).map_err(|e| JasperError::io(file, e))?;
+    info!("Stamped pattern db ({}) with sha256:{}", file, meta.sha256);
+    Ok(())
+}
+
+/// Print entry names and sequence lengths of a pattern db, the sequences are not shown.
+pub fn db_info(file: &str, key_source: &KeySource) -> Result<()> {
+    let content = read_db_content(file, key_source)?;
+    let (meta, body) = DbMeta::parse(&content);
+    info!("pattern db ({}) {}", file, meta.to_info());
+    let header_lines = content[..content.len() - body.len()].iter().filter(|b| **b == b'\n').count() as u64;
+    let entries = parse_db_entries(file, body, header_lines)?;
+    println!("name\tlength");
+    for (name, seq) in &entries {
+        println!("{}\t{}", name, seq.trim().len());
+    }
+    info!("{} entries in pattern db ({})", entries.len(), file);
+    Ok(())
+}
+
+
+fn parse_insert_range(expected_len: Option<&str>, min_len: Option<&str>, max_len: Option<&str>, len_tolerance: f32) -> std::result::Result<Option<(usize, usize)>, String> {
+    let parse = |value: &str| value.parse::<usize>().map_err(|_| format!("invalid insert length ({})", value));
+    let mut range = match expected_len {
+        Some(value) => match value.split_once('-') {
+            Some((min, max)) => Some((parse(min)?, parse(max)?)),
+            None => {
+                let len = parse(value)? as f32;
+                Some(((len * (1.0 - len_tolerance)).floor() as usize, (len * (1.0 + len_tolerance)).ceil() as usize))
+            }
+        },
+        None => None,
+    };
+    if min_len.is_some() || max_len.is_some() {
+        let (min, max) = range.unwrap_or((0, usize::MAX));
+        range = Some((min_len.map(parse).transpose()?.unwrap_or(min), max_len.map(parse).transpose()?.unwrap_or(max)));
     }
+    Ok(range)
 }
 
 #[derive(Debug,Clone)]
 pub struct FusionDB {
-    pub fusion_db: HashMap<String, String>,
-}    // fusion patterns to find
+    pub fusion_db: PatternDB,
+}    // fusion patterns to find, searched as f_patterns
 impl FusionDB {
 fn new() -> FusionDB {
     FusionDB {
-        fusion_db: HashMap::new(),
+        fusion_db: PatternDB::new(),
     }
 }
 pub fn is_empty(&self) -> bool {
-    self.fusion_db.is_empty()
+    self.fusion_db.f_patterns.is_empty()
 }
 
-fn loading_pattern_db(&self, file: &str) -> HashMap<String, String> {
-    //loading pattern db file
-    let mut pattern_db = HashMap::new();
-    let mut rdr = csv::ReaderBuilder::new()
-        .has_headers(false)
-        .delimiter(b'\t')
-        .from_path(file)
-        .expect(&format!("no such file({}) found", file));
-    // info!("loading pattern db file success({})...", file);
-    for result in rdr.records() {
-        let recored = result.unwrap();
-        let name = &recored[0];
-        let seq = &recored[1];
-        pattern_db.insert(name.to_string(), seq.to_string());
-    }
-    // debug!("pattern_db is {:?}", pattern_db);
-    return pattern_db;
-}
-fn loading_pattern(&mut self, file: &str, pattern_db: HashMap<String, String>){
+fn loading_pattern(&mut self, file: &str, db_file: &str, pattern_db: &HashMap<String, String>, key_source: &KeySource) -> Result<()> {
     //loading tsv file
-    // let file = File::open(file).unwrap();
-    let mut rdr = csv::ReaderBuilder::new()
-        .has_headers(true)
-        .delimiter(b'\t')
-        .from_path(file)
-        .expect(&format!("no such file({}) found", file));
-    for result in rdr.records() {
-        let record = result.unwrap();
+    let (headers, mut rows) = read_list(file, key_source)?;
+    // a header without `#` is a column name and no db entry
+    if headers.is_empty() && rows.first().is_some_and(|(_, fields)| column_like(&fields[0]) && !pattern_db.contains_key(&fields[0])) {
+        info!("first row of ({}) is taken as header: {}", file, rows.remove(0).1.join(", "));
+    }
+    for (line, record) in rows {
         let fusion_pattern = record[0].to_string();
         let fusion_seq = pattern_db
             .get(&fusion_pattern)
-            .expect(&format!("no such fusion pattern({}) in pattern", fusion_pattern))
+            .ok_or_else(|| JasperError::MissingPattern {
+                file: file.to_string(),
+                line,
+                name: fusion_pattern.clone(),
+                db: db_file.to_string(),
+            })?
             .to_string();
-        self.fusion_db.insert(fusion_pattern.clone(), fusion_seq.clone());
+        self.fusion_db.f_patterns.insert(fusion_pattern.clone(), fusion_seq.clone());
     }
+    Ok(())
 }    
-pub fn get_pattern(&mut self, pattern_db_file: &String, pattern_file: &String){
-    let pattern_db = self.loading_pattern_db(pattern_db_file);
+pub fn get_pattern(&mut self, db_file: &str, pattern_db: &HashMap<String, String>, pattern_file: &str, key_source: &KeySource) -> Result<()> {
     self.loading_pattern(
         pattern_file,
+        db_file,
         pattern_db,
-    );
+        key_source,
+    )
 }
 }
 
-pub fn get_patterns(inputargs: &Args) -> PatternArgs {
-    info!("loading pattern db file({})...", inputargs.pattern_db_file.as_ref().unwrap());
+pub fn get_patterns(inputargs: &Args) -> Result<PatternArgs> {
+    let pattern_db_files = inputargs.pattern_db_files.clone().unwrap_or_default();
+    let pattern_files = inputargs.pattern_files.clone().unwrap_or_default();
+    let kits = find_kits(&inputargs.kits)?;
+    let db_names = db_names(&pattern_db_files, &kits);
+    let db_file = db_names.join(", ");
+    info!("loading pattern db file({})...", db_file);
     let mut patternargs = PatternArgs::new(inputargs);
+    let (db_meta, pattern_db) = read_pattern_dbs(&pattern_db_files, &kits, &patternargs.key_source)?;
+    for (file, db_meta) in db_names.iter().zip(&db_meta) {
+        info!("pattern db ({}) {}", file, db_meta.to_info());
+    }
+    patternargs.db_meta = db_meta;
 
     let mut fusion_db = FusionDB::new();
-    if &inputargs.fusion_file != "" {
-        fusion_db.get_pattern(&inputargs.pattern_db_file.as_ref().unwrap(), &inputargs.fusion_file);
+    if !inputargs.fusion_file.is_empty() {
+        fusion_db.get_pattern(&db_file, &pattern_db, &inputargs.fusion_file, &patternargs.key_source)?;
         patternargs.fusion_db = fusion_db;
     }
-    for i in 0..inputargs.pattern_files.as_ref().unwrap().len() {
+    // levels of the pattern files come first, the kits add one level after them
+    for i in 0..pattern_files.len() + usize::from(!kits.is_empty()) {
         let mut patterndb = PatternDB::new();
-        patterndb.get_pattern(&inputargs.pattern_db_file.as_ref().unwrap(), &inputargs.pattern_files.as_ref().unwrap()[i]);
+        match pattern_files.get(i) {
+            Some(pattern_file) => patterndb.get_pattern(&db_file, &pattern_db, pattern_file, inputargs.len_tolerance, &patternargs.key_source)?,
+            None => patterndb.get_kits_pattern(&kits, &db_file, &pattern_db, inputargs.len_tolerance)?,
+        }
         let patternarg = PatternArg {
             pattern_db: patterndb,
             pattern_pos: inputargs.pattern_pos,
-            pattern_errate: patternargs.pattern_errate[i].clone(),
-            pattern_maxdist: patternargs.pattern_maxdist[i].clone(),
-            pattern_shift: patternargs.pattern_shift[i].clone(),
+            pattern_errate: patternargs.pattern_errate[i],
+            pattern_maxdist: patternargs.pattern_maxdist[i],
+            pattern_shift: patternargs.pattern_shift[i],
+            min_overlap: if inputargs.end_match { inputargs.min_overlap } else { 0 },
+            window_size: patternargs.level_window[i],
         };
         patternargs.pattern_vec.push(patternarg);
     };
-    return patternargs;
+    patternargs.pattern_db = pattern_db;
+    Ok(patternargs)
 }
 
 
@@ -264,19 +714,28 @@ pub fn get_patterns(inputargs: &Args) -> PatternArgs {
 #[test]
 pub fn test0(){
     use clap::Parser;
-    let args = crate::args::Args::parse();
-    let search_patterns = get_patterns(&args);
+    let args = crate::args::Args::parse_from(["jasper", "-p", "example/primer.list", "example/cyclone_barcode.list", "-d", "example/pattern.db"]);
+    let search_patterns = get_patterns(&args).unwrap();
     info!("{:?}", search_patterns);
 }
+
+#[test]
+pub fn test_level_window(){
+    use clap::Parser;
+    let args = crate::args::Args::parse_from(["jasper", "-p", "example/primer.list", "-d", "example/pattern.db", "-w", "300,200", "--level-window", "100,100"]);
+    let patternargs = PatternArgs::new(&args);
+    assert_eq!(patternargs.level_window[..3], [(100, 100), (300, 200), (300, 200)]);
+}
 #[test]
 pub fn test1(){
-    pretty_env_logger::init();
+    let _ = pretty_env_logger::try_init();
     // use clap::Parser;
     // let args = crate::args::Args::parse();
     let mut patterndb = PatternDB::new();
-    let db = "/home/jiangchen/project/jasper/example/pattern.db".to_string();
-    let file = "/home/jiangchen/project/jasper/example/primer.list".to_string();
-    patterndb.get_pattern(&db, &file);
+    let db = "example/pattern.db".to_string();
+    let pattern_db = read_pattern_db(&db, &KeySource::default()).unwrap().1;
+    let file = "example/primer.list".to_string();
+    patterndb.get_pattern(&db, &pattern_db, &file, 0.1, &KeySource::default()).unwrap();
     info!("{:?}", patterndb);
 }
 
@@ -284,14 +743,105 @@ pub fn test1(){
 
 
 
+#[test]
+pub fn test_insert_range(){
+    assert_eq!(parse_insert_range(Some("1000"), None, None, 0.1), Ok(Some((900, 1100))));
+    assert_eq!(parse_insert_range(Some("1300-1400"), None, None, 0.1), Ok(Some((1300, 1400))));
+    assert_eq!(parse_insert_range(None, Some("200"), None, 0.1), Ok(Some((200, usize::MAX))));
+    assert_eq!(parse_insert_range(None, None, None, 0.1), Ok(None));
+    assert!(parse_insert_range(Some("abc"), None, None, 0.1).is_err());
+}
+
+#[test]
+pub fn test_errate(){
+    assert_eq!(parse_errate("0.1"), Some((0.1, 0.1)));
+    assert_eq!(parse_errate("0.1, 0.2"), Some((0.1, 0.2)));
+    assert_eq!(parse_errate("0.6"), None);
+    assert_eq!(parse_errate("abc"), None);
+}
+
 #[test]
 pub fn test2(){
-    pretty_env_logger::init();
+    let _ = pretty_env_logger::try_init();
     // use clap::Parser;
     // let args = crate::args::Args::parse();
     let mut fusion_db = FusionDB::new();
-    let db = "/home/jiangchen/project/jasper/example/pattern.db".to_string();
-    let file = "/home/jiangchen/project/jasper/example/fusion.list".to_string();
-    fusion_db.get_pattern(&db, &file);
+    let db = "example/pattern.db".to_string();
+    let pattern_db = read_pattern_db(&db, &KeySource::default()).unwrap().1;
+    let file = "example/fusion.list".to_string();
+    fusion_db.get_pattern(&db, &pattern_db, &file, &KeySource::default()).unwrap();
     info!("{:?}", fusion_db);
 }
+
+#[test]
+pub fn test_db_meta(){
+    let content = b"##kit=demo\n##version=1.0\n##checksum=sha256:UNKNOWN\nCB01\tACGT\n";
+    let (meta, body) = DbMeta::parse(content);
+    assert_eq!((meta.kit.as_str(), meta.version.as_str(), meta.date.as_str()), ("demo", "1.0", ""));
+    assert_eq!(body, b"CB01\tACGT\n");
+    assert_eq!(meta.sha256, format!("{:x}", Sha256::digest(b"CB01\tACGT\n")));
+    assert!(!meta.verified());
+}
+
+#[test]
+pub fn test_fasta_db(){
+    let body = b">CB01 barcode 1\nACGT\nACGT\n>CB02\nTTTT\n";
+    let entries = parse_db_entries("kit.fa", body, 0).unwrap();
+    assert_eq!(entries, vec![("CB01".to_string(), "ACGTACGT".to_string()), ("CB02".to_string(), "TTTT".to_string())]);
+    assert!(parse_db_entries("kit.fa", b">CB01\n>CB02\nTTTT\n", 0).is_err());
+    let error = parse_db_entries("kit.db", b"CB01\tACGT\nX1\tACGTRACG\n", 2).unwrap_err();
+    assert!(matches!(error, JasperError::Parse { line: 4, .. }));
+    assert!(parse_db_entries("kit.fa", b">CB01\nAC GT\n", 0).is_err());
+}
+
+#[test]
+pub fn test_kits(){
+    // every built-in kit loads and its db header matches its content
+    for kit in crate::kit::KITS {
+        let (meta, pattern_db) = parse_pattern_db(kit.name, kit.db.as_bytes()).unwrap();
+        assert!(meta.verified());
+        let mut patterndb = PatternDB::new();
+        patterndb.get_kit_pattern(kit, kit.name, &pattern_db, 0.1).unwrap();
+        assert!(!patterndb.pattern_type.is_empty());
+    }
+    let pattern_db = parse_pattern_db("ont_barcode", crate::kit::KITS[0].db.as_bytes()).unwrap().1;
+    assert_eq!(pattern_db["NB23"], "CTTACTACCCAGAACACACCGGAG");
+    // kits given together share one level
+    use clap::Parser;
+    let args = crate::args::Args::parse_from(["jasper", "--kit", "EXP-NBD104", "EXP-NBD114"]);
+    let patternargs = get_patterns(&args).unwrap();
+    assert_eq!(patternargs.pattern_vec.len(), 1);
+    let pattern_type = &patternargs.pattern_vec[0].pattern_db.pattern_type;
+    assert!(pattern_type.contains_key("NB05_NB05") && pattern_type.contains_key("NB13_NB13"));
+    // the same pair named by two kits is refused
+    let args = crate::args::Args::parse_from(["jasper", "--kit", "SQK-NBD114-24", "EXP-PBC001"]);
+    assert!(matches!(get_patterns(&args), Err(JasperError::Config(_))));
+}
+
+#[test]
+pub fn test_list_header(){
+    // a leading comment is not the header, the named column line is
+    let (headers, rows) = parse_list("#primer list v2\n#F\tR\tname\terrate\nCB01\tCB01\tbc01\t0.1\n");
+    assert_eq!(headers, vec!["F", "R", "name", "errate"]);
+    assert_eq!(rows.len(), 1);
+    // a commented out row after the header is not the header
+    let (headers, rows) = parse_list("#F\tR\ttype\n#CB00\tCB00\tbc00\nCB01\tCB01\tbc01\n");
+    assert_eq!(headers, vec!["F", "R", "type"]);
+    assert_eq!(rows[0].0, 3);
+    // no header: the first row is data
+    let (headers, rows) = parse_list("CB01\tCB01\tbc01\nCB02\tCB02\tbc02\n");
+    assert!(headers.is_empty());
+    assert_eq!(rows.len(), 2);
+    let (headers, rows) = parse_list("F\tR\tname\talias\nCB01\tCB01\tbc01\tS1\n");
+    assert_eq!(headers[3], "alias");
+    assert_eq!(rows.len(), 1);
+    // a header row without `#` is taken by its column names, a typo in the first row is no header
+    let pattern_db = read_pattern_db("example/pattern.db", &KeySource::default()).unwrap().1;
+    let mut patterndb = PatternDB::new();
+    let list = parse_list("primary_F\tprimary_R\ttype\nCB01\tCB01\tbc01\n");
+    patterndb.loading_pattern("list", list, "db", &pattern_db, 0.1).unwrap();
+    assert_eq!(patterndb.pattern_type.len(), 1);
+    let list = parse_list("CBX1\tCBX1\tbc01\nCB02\tCB02\tbc02\n");
+    let error = PatternDB::new().loading_pattern("list", list, "db", &pattern_db, 0.1).unwrap_err();
+    assert!(matches!(error, JasperError::MissingPattern { line: 1, .. }));
+}
@@ -1,7 +1,7 @@
 use crate::fastq::ReadInfo;
-use crate::myers::myers_best;
+use crate::myers::{myers_best, myers_end_anchored};
 use crate::myers::SearchPattern;
-use crate::pattern::{PatternArg, PatternArgs};
+use crate::pattern::{PatternArg, PatternArgs, PatternDB, PatternType};
 use bio::io::fastq::Record;
 use flume::Receiver;
 use log::info;
@@ -17,22 +17,18 @@ struct ReadChunk {
     pos_mut: bool,
 }
 impl ReadChunk {
-    pub fn new(patternargs: &PatternArgs, readinfo: &ReadInfo) -> Self {
-        let left = if patternargs.window_size[0] > readinfo.read_len {
-            readinfo.read_len
+    pub fn new(window_size: (usize, usize), read_len: usize) -> Self {
+        let left = if window_size.0 > read_len {
+            read_len
         } else {
-            patternargs.window_size[0]
+            window_size.0
         };
 
-        let right = if patternargs.window_size[1] > readinfo.read_len {
-            0
-        } else {
-            readinfo.read_len - patternargs.window_size[1]
-        };
+        let right = read_len.saturating_sub(window_size.1);
 
         ReadChunk {
-            left: left,
-            right: right,
+            left,
+            right,
             pos_mut: false,
         }
     }
@@ -40,10 +36,12 @@ impl ReadChunk {
 
 #[derive(Debug)]
 pub struct SplitType {
-    pub patter_match: &'static str, // single or dual
+    pub patter_match: &'static str, // dual, left, right, or complete for a row with one side only
     pub pattern_name: String,       // pattern name ex:4.2-F_3.7-R
     pub pattern_type: String,       // pattern type ex:alpha
     pub pattern_strand: String,         // strand orientation
+    pub insert_range: Option<(usize, usize)>, // expected insert length of the pattern pair
+    pub alias: Option<String>,          // sample alias of the pattern pair
     pub left_matcher: Matcher,          // matcher
     pub right_matcher: Matcher,         // matcher
 }
@@ -54,12 +52,14 @@ impl SplitType {
             pattern_name: String::from("unknown"),
             pattern_type: String::from("unknown"),
             pattern_strand: String::from("unknown"),
-            left_matcher: left_matcher,
-            right_matcher: right_matcher,
+            insert_range: None,
+            alias: None,
+            left_matcher,
+            right_matcher,
         }
     }
     pub fn to_info(&self) -> String {
-        return format!(
+        format!(
             "{}\t{}\t{}\t{}:({},{},{},{});({},{},{},{})",
             self.patter_match,
             self.pattern_name,
@@ -74,32 +74,48 @@ impl SplitType {
             self.right_matcher.ystart,
             self.right_matcher.yend,
         )
-        .to_string();
+        .to_string()
     }
     pub fn anno_pattern_type(
         &mut self,
-        pattern_type_dict: &HashMap<String, (String, String, String)>,
+        pattern_type_dict: &HashMap<String, PatternType>,
         pattern_maxdist: i32,
-    ) -> (){
+    ){
         let (patter_match, key) =
             self.get_match_key(pattern_maxdist, pattern_type_dict);
-            if key == "_".to_string() || key == "unknown".to_string(){
+            if key == "_" || key == "unknown"{
                 return;
             }
-            for (dict_key, value) in pattern_type_dict {
-                if dict_key.contains(&key) {
-                    self.patter_match = patter_match;
-                    self.pattern_name = value.0.clone();
-                    self.pattern_type = value.1.clone();
-                    self.pattern_strand = value.2.clone();
-                break;
+            // rows with one side only are complete with a single pattern, looked up by the exact key
+            let single_keys = [
+                (self.left_matcher.status, format!("{}_", self.left_matcher.pattern)),
+                (self.right_matcher.status, format!("_{}", self.right_matcher.pattern)),
+            ];
+            let found = if let Some(value) = pattern_type_dict.get(&key).filter(|_| patter_match == "dual") {
+                Some((patter_match, value))
+            } else if let Some(value) = single_keys.iter().filter(|(status, _)| *status).find_map(|(_, key)| pattern_type_dict.get(key)) {
+                Some(("complete", value))
+            } else {
+                pattern_type_dict.iter().find(|(dict_key, _)| dict_key.contains(&key)).map(|(_, value)| (patter_match, value))
+            };
+            let Some((patter_match, value)) = found else {
+                return;
+            };
+            // a pair restricted to one orientation is not accepted in the other one
+            if value.strand_restrict.as_ref().is_some_and(|strand| *strand != value.pattern_strand && value.pattern_strand != "unknown") {
+                return;
             }
-        }
+            self.patter_match = patter_match;
+            self.pattern_name = value.pattern_name.clone();
+            self.pattern_type = value.pattern_type.clone();
+            self.pattern_strand = value.pattern_strand.clone();
+            self.insert_range = value.insert_range;
+            self.alias = value.alias.clone();
     }
     pub fn get_match_key(
         &self,
         pattern_maxdist: i32,
-        pattern_type_dict: &HashMap<String, (String, String, String)>,
+        pattern_type_dict: &HashMap<String, PatternType>,
     ) -> (&'static str, String) {
         if self.right_matcher.status && self.left_matcher.status {
             let combined_pattern = format!("{}_{}", self.left_matcher.pattern, self.right_matcher.pattern);
@@ -119,19 +135,19 @@ impl SplitType {
                 ("right", format!("_{}", self.right_matcher.pattern))
             }
         }else if self.right_matcher.status {
-            return (
+            (
                 "right",
                 format!("_{}", self.right_matcher.pattern),
             )
         }else if self.left_matcher.status {
-            return (
+            (
                 "left",
                 format!("{}_", self.left_matcher.pattern),
-            );
+            )
         }else {
-            return (
+            (
                 "unknown",
-                String::from("unknown"));
+                String::from("unknown"))
         }
 
     }
@@ -175,7 +191,7 @@ fn calculate_start_end(
         }
         "right" => {
             new_end = min(text_len, new_start + pattern_len + pattern_shift);
-            new_start = start.checked_sub(pattern_shift).unwrap_or(0);
+            new_start = start.saturating_sub(pattern_shift);
         }
         _ => {}
     };
@@ -185,14 +201,26 @@ fn calculate_start_end(
 fn find_matcher(
     rawstart: usize,
     rawend: usize,
-    patterndb: &HashMap<String, String>,
+    patterndb: &PatternDB,
     search_pattern: &mut SearchPattern,
     mut_pos: bool,
     pattern_shift: usize,
     orient: &'static str,
 ) -> Matcher {
     let mut matcher = Matcher::new();
-    for (key, value) in patterndb.iter() {
+    let patterns = if orient == "right" { &patterndb.r_patterns } else { &patterndb.f_patterns };
+    // per pattern tolerance of the list replaces the level errate
+    let level_ratio = search_pattern.dist_ratio;
+    let set_tolerance = |search_pattern: &mut SearchPattern, key: &str| {
+        let errate = patterndb.pattern_errate.get(key);
+        search_pattern.dist_ratio = errate.map_or(level_ratio, |errate| if orient == "right" { errate.1 } else { errate.0 });
+    };
+    let set_max_dist = |search_pattern: &mut SearchPattern, key: &str| {
+        if let Some(max_dist) = patterndb.pattern_max_dist.get(key) {
+            search_pattern.max_dist = *max_dist;
+        }
+    };
+    for (key, value) in patterns.iter() {
         let pattern = value.as_bytes().to_vec();
         let (start_pos, end_pos) = if mut_pos {
             calculate_start_end(
@@ -206,23 +234,52 @@ fn find_matcher(
         } else {
             (rawstart, rawend)
         };
+        set_tolerance(search_pattern, key);
         search_pattern.update(start_pos, end_pos, pattern);
+        set_max_dist(search_pattern, key);
 
         // debug!("search_pattern: {:?}", search_pattern);
-        let result = myers_best(&search_pattern);
+        let result = myers_best(search_pattern);
         // debug!("get result: {:?}", result);
-        if result.is_none() {
+        let Some((score, ystart, yend)) = result else {
             // debug!("no match found continue");
             continue;
-        } else if result.unwrap().0 < matcher.score {
+        };
+        if score < matcher.score {
             matcher.pattern = key.to_string();
-            matcher.score = result.unwrap().0;
-            matcher.ystart = result.unwrap().1;
-            matcher.yend = result.unwrap().2;
+            matcher.score = score;
+            matcher.ystart = ystart;
+            matcher.yend = yend;
             matcher.status = true;
             // debug!("get better matcher: {:?}", matcher);
         }
     }
+    // patterns cut by the read boundary, only tried when no full match found
+    let at_boundary = match orient {
+        "left" => rawstart == 0,
+        "right" => rawend == search_pattern.raw_text_len,
+        _ => false,
+    };
+    if matcher.status || search_pattern.min_overlap == 0 || mut_pos || !at_boundary {
+        search_pattern.dist_ratio = level_ratio;
+        return matcher;
+    }
+    for (key, value) in patterns.iter() {
+        set_tolerance(search_pattern, key);
+        search_pattern.update(rawstart, rawend, value.as_bytes().to_vec());
+        set_max_dist(search_pattern, key);
+        let Some((score, ystart, yend)) = myers_end_anchored(search_pattern, orient) else {
+            continue;
+        };
+        if score < matcher.score {
+            matcher.pattern = key.to_string();
+            matcher.score = score;
+            matcher.ystart = ystart;
+            matcher.yend = yend;
+            matcher.status = true;
+        }
+    }
+    search_pattern.dist_ratio = level_ratio;
     matcher
 }
 
@@ -230,10 +287,11 @@ fn splitter(record: &Record, readchunk: &ReadChunk, patternarg1: &PatternArg) ->
     let patterndb = &patternarg1.pattern_db;
     let mut search_pattern =
         SearchPattern::new(record.seq().to_vec(), patternarg1.pattern_errate.0);
+    search_pattern.min_overlap = patternarg1.min_overlap;
     let left_matcher = find_matcher(
         0,
         readchunk.left,
-        &patterndb.f_patterns,
+        patterndb,
         &mut search_pattern,
         readchunk.pos_mut,
         patternarg1.pattern_shift,
@@ -245,7 +303,7 @@ fn splitter(record: &Record, readchunk: &ReadChunk, patternarg1: &PatternArg) ->
     let right_matcher = find_matcher(
         readchunk.right,
         record.seq().len(),
-        &patterndb.r_patterns,
+        patterndb,
         &mut search_pattern,
         readchunk.pos_mut,
         patternarg1.pattern_shift,
@@ -256,14 +314,27 @@ fn splitter(record: &Record, readchunk: &ReadChunk, patternarg1: &PatternArg) ->
     let mut split_type = SplitType::new(left_matcher, right_matcher);
     split_type.anno_pattern_type(&patterndb.pattern_type, patternarg1.pattern_maxdist as i32);
     // debug!("read1: {:?}", split_type);
-    return split_type;
+    split_type
 }
 
 pub fn splitter_vec(readinfo: &ReadInfo, patternargs: &PatternArgs) -> Vec<SplitType> {
     let mut split_type_vec = Vec::new();
-    let mut readchunk = ReadChunk::new(patternargs, readinfo);
-    for patternarg in patternargs.pattern_vec.iter() {
-        let split_type = splitter(&readinfo.record, &readchunk, &patternarg);
+    let mut readchunk = ReadChunk::new(patternargs.pattern_vec[0].window_size, readinfo.read_len);
+    for (i, patternarg) in patternargs.pattern_vec.iter().enumerate() {
+        if i > 0 && !readchunk.pos_mut {
+            readchunk = ReadChunk::new(patternarg.window_size, readinfo.read_len);
+        }
+        let mut split_type = splitter(&readinfo.record, &readchunk, patternarg);
+        // widen the window step by step up to the whole read when nothing is found
+        let mut window_size = patternarg.window_size;
+        while patternargs.window_adaptive
+            && !readchunk.pos_mut
+            && split_type.patter_match == "unknown"
+            && (window_size.0 < readinfo.read_len || window_size.1 < readinfo.read_len)
+        {
+            window_size = (window_size.0.max(1) * 2, window_size.1.max(1) * 2);
+            split_type = splitter(&readinfo.record, &ReadChunk::new(window_size, readinfo.read_len), patternarg);
+        }
         // debug!("split_type: {:?}", split_type);
         if patternarg.pattern_pos
             && split_type.left_matcher.status
@@ -271,15 +342,53 @@ pub fn splitter_vec(readinfo: &ReadInfo, patternargs: &PatternArgs) -> Vec<Split
             {
             // let right_bound: usize = if right <= record.seq().len() - 30 { right + 30 } else { record.seq().len() };
 
-            readchunk.left = split_type.left_matcher.ystart.clone();
-            readchunk.right = split_type.right_matcher.yend.clone();
+            readchunk.left = split_type.left_matcher.ystart;
+            readchunk.right = split_type.right_matcher.yend;
             readchunk.pos_mut = true
         } else {
-            readchunk = ReadChunk::new(patternargs, readinfo);
+            readchunk.pos_mut = false;
         }
         split_type_vec.push(split_type);
     }
-    return split_type_vec;
+    split_type_vec
+}
+
+fn find_poly_run(seq: &[u8], start: usize, end: usize, base: u8, min_len: usize) -> Option<(usize, usize)> {
+    // max-scoring segment of base(+1) against others(-2), tolerates ONT errors and short indels
+    let (mut best, mut best_range) = (0, None);
+    let (mut score, mut run_start) = (0i32, start);
+    for (i, b) in seq.iter().enumerate().take(end).skip(start) {
+        if score <= 0 {
+            score = 0;
+            run_start = i;
+        }
+        score += if b.eq_ignore_ascii_case(&base) { 1 } else { -2 };
+        if score > best {
+            best = score;
+            best_range = Some((run_start, i + 1));
+        }
+    }
+    best_range.filter(|(run_start, run_end)| run_end - run_start >= min_len)
+}
+
+pub fn polya_detect(readinfo: &ReadInfo, patternargs: &PatternArgs) -> Option<(u8, usize, usize)> {
+    // search polyA before the 3' pattern and polyT after the 5' pattern of the first level
+    let seq = readinfo.record.seq();
+    let split_type = &readinfo.split_type_vec[0];
+    let left = if split_type.left_matcher.status { split_type.left_matcher.yend } else { 0 };
+    let right = if split_type.right_matcher.status { split_type.right_matcher.ystart } else { seq.len() };
+    if right <= left {
+        return None;
+    }
+    let window = patternargs.polya_window;
+    let polya = find_poly_run(seq, right.saturating_sub(window).max(left), right, b'A', patternargs.polya_min);
+    let polyt = find_poly_run(seq, left, min(left + window, right), b'T', patternargs.polya_min);
+    match (polya, polyt) {
+        (Some(a), Some(t)) if t.1 - t.0 > a.1 - a.0 => Some((b'T', t.0, t.1)),
+        (Some(a), _) => Some((b'A', a.0, a.1)),
+        (None, Some(t)) => Some((b'T', t.0, t.1)),
+        (None, None) => None,
+    }
 }
 
 fn fusion_detect(readinfo: &ReadInfo, patternargs: &PatternArgs) -> bool {
@@ -312,6 +421,27 @@ fn fusion_detect(readinfo: &ReadInfo, patternargs: &PatternArgs) -> bool {
 
 
 
+fn split_read(readinfo: &mut ReadInfo, patternargs: &PatternArgs) {
+    readinfo.split_type_vec = splitter_vec(readinfo, patternargs);
+    if patternargs.polya {
+        readinfo.polya = polya_detect(readinfo, patternargs);
+    }
+    // get split_type_vec annotation
+    readinfo.update(patternargs);
+}
+
+fn rescue_read(readinfo: ReadInfo, rescue_args: &PatternArgs) -> ReadInfo {
+    // second pass of unknown reads with relaxed parameters, keep it only when it becomes valid
+    let mut rescued = ReadInfo::new(readinfo.record.clone());
+    split_read(&mut rescued, rescue_args);
+    if rescued.read_type == "valid" {
+        rescued.rescued = true;
+        rescued
+    } else {
+        readinfo
+    }
+}
+
 pub fn splitter_receiver(
     rrx: Receiver<ReadInfo>,
     patternargs: &PatternArgs,
@@ -323,15 +453,18 @@ pub fn splitter_receiver(
         let rrx = rrx.clone();
         let stx = stx.clone();
         let patternargs = patternargs.clone();
+        let rescue_args = patternargs.rescue.then(|| patternargs.rescue_args());
         thread::spawn(move || {
             let mut read_count = 0;
             for mut readinfo in rrx.iter() {
-                readinfo.split_type_vec = splitter_vec(&readinfo, &patternargs);
-                // get split_type_vec annotation
-                readinfo.update(&patternargs.pattern_match,&patternargs.write_type,patternargs.trim_n, patternargs.min_length, &patternargs.id_sep);
+                split_read(&mut readinfo, &patternargs);
+                if let Some(rescue_args) = &rescue_args {
+                    if readinfo.read_type == "unknown" {
+                        readinfo = rescue_read(readinfo, rescue_args);
+                    }
+                }
                 if !patternargs.fusion_db.is_empty() && fusion_detect(&readinfo, &patternargs) {
-                    readinfo.read_type = "fusion".into();
-                    readinfo.write_to_fq = false;
+                    readinfo.set_fusion(&patternargs);
                 }
                 
                 // info!("read1: {}", matched_reads.to_tsv());
@@ -347,3 +480,11 @@ pub fn splitter_receiver(
     }
     srx
 }
+
+#[test]
+pub fn test_find_poly_run(){
+    let seq = b"CCGTACGTAAAAAAAAAACAAAAAAAGTTCG";
+    assert_eq!(find_poly_run(seq, 0, seq.len(), b'A', 15), Some((8, 26)));
+    assert_eq!(find_poly_run(seq, 0, seq.len(), b'A', 20), None);
+    assert_eq!(find_poly_run(seq, 0, seq.len(), b'T', 5), None);
+}
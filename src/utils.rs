@@ -1,23 +1,106 @@
 use log::info;
 
 pub fn reverse_complement(seq: &str) -> String {
-    let mut complement = vec![' '; seq.len()];
-    for (i, c) in seq.chars().enumerate() {
-        complement[seq.len() - 1 - i] = match c {
-            'A' => 'T',
-            'T' => 'A',
-            'C' => 'G',
-            'G' => 'C',
-            'a' => 't',
-            't' => 'a',
-            'c' => 'g',
-            'g' => 'c',
-            _ => panic!("Invalid character: {}", c),
-        };
+    String::from_utf8(reverse_complement_bytes(seq.as_bytes())).expect("complement is ascii")
+}
+
+pub fn reverse_complement_bytes(seq: &[u8]) -> Vec<u8> {
+    seq.iter()
+        .rev()
+        .map(|base| match base {
+            b'A' => b'T',
+            b'T' => b'A',
+            b'C' => b'G',
+            b'G' => b'C',
+            b'a' => b't',
+            b't' => b'a',
+            b'c' => b'g',
+            b'g' => b'c',
+            b'n' => b'n',
+            // IUPAC codes and anything else in a read become N instead of stopping the thread
+            _ => b'N',
+        })
+        .collect()
+}
+
+pub fn edit_distance(a: &[u8], b: &[u8]) -> usize {
+    // levenshtein distance, N matches any base like in the myers search
+    let same = |x: u8, y: u8| x.eq_ignore_ascii_case(&y) || x.eq_ignore_ascii_case(&b'N') || y.eq_ignore_ascii_case(&b'N');
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    let mut curr = vec![0; b.len() + 1];
+    for (i, &x) in a.iter().enumerate() {
+        curr[0] = i + 1;
+        for (j, &y) in b.iter().enumerate() {
+            let cost = if same(x, y) { 0 } else { 1 };
+            curr[j + 1] = (prev[j] + cost).min(prev[j + 1] + 1).min(curr[j] + 1);
+        }
+        std::mem::swap(&mut prev, &mut curr);
     }
-    complement.into_iter().collect::<String>()
+    prev[b.len()]
 }
 
+pub fn mean_quality(qual: &[u8]) -> f64 {
+    // average error probability of phred+33 qualities, converted back to phred
+    if qual.is_empty() {
+        return 0.0;
+    }
+    let error_sum: f64 = qual.iter().map(|q| 10f64.powf(-((q.saturating_sub(33)) as f64) / 10.0)).sum();
+    -10.0 * (error_sum / qual.len() as f64).log10()
+}
+pub fn max_homopolymer(seq: &[u8]) -> usize {
+    let mut max_run = 0;
+    let mut run = 0;
+    for (i, base) in seq.iter().enumerate() {
+        if i > 0 && seq[i - 1].eq_ignore_ascii_case(base) {
+            run += 1;
+        } else {
+            run = 1;
+        }
+        max_run = max_run.max(run);
+    }
+    max_run
+}
+pub fn quality_trim(qual: &[u8], method: &str, threshold: u8, window: usize) -> (usize, usize) {
+    // kept range of phred+33 qualities, <window> trims ends until the window mean reaches threshold,
+    // <mott> keeps the max-scoring segment of (q - threshold)
+    let quals: Vec<i64> = qual.iter().map(|q| q.saturating_sub(33) as i64).collect();
+    let threshold = threshold as i64;
+    match method {
+        "window" => {
+            let window = window.clamp(1, quals.len().max(1));
+            let passed = |start: usize| {
+                let slice = &quals[start..(start + window).min(quals.len())];
+                slice.iter().sum::<i64>() >= threshold * slice.len() as i64
+            };
+            let Some(start) = (0..quals.len()).find(|&i| passed(i)) else {
+                return (0, 0);
+            };
+            let end = (start..quals.len())
+                .rev()
+                .find(|&i| passed(i.saturating_sub(window - 1).max(start)))
+                .map(|i| i + 1)
+                .unwrap_or(start);
+            (start, end)
+        }
+        "mott" => {
+            let (mut best, mut best_range) = (0, (0, 0));
+            let (mut score, mut start) = (0, 0);
+            for (i, q) in quals.iter().enumerate() {
+                if score <= 0 {
+                    score = 0;
+                    start = i;
+                }
+                score += q - threshold;
+                if score > best {
+                    best = score;
+                    best_range = (start, i + 1);
+                }
+            }
+            best_range
+        }
+        _ => (0, quals.len()),
+    }
+}
 
 pub struct ProcessInfo {
     start_time: std::time::Instant,
@@ -31,12 +114,12 @@ impl ProcessInfo {
             start_time: std::time::Instant::now(),
             end_time: std::time::Instant::now(),
             process_num: 0,
-            info_num: info_num,
+            info_num,
         }
     }
     pub fn info(&mut self){
         self.process_num+=1;
-        if self.process_num % self.info_num ==0 {
+        if self.process_num.is_multiple_of(self.info_num) {
             self.end_time = std::time::Instant::now();
             let elapsed = self.end_time.duration_since(self.start_time);
             let rate = self.process_num as f64 / elapsed.as_secs_f64();
@@ -47,3 +130,44 @@ impl ProcessInfo {
     }
     
 }
+
+#[test]
+pub fn test_mean_quality(){
+    // Q10 and Q30 average in probability space, not (10+30)/2
+    let qual = [b'+', b'?'];
+    assert!((mean_quality(&qual) - 12.967).abs() < 0.001);
+    assert_eq!(mean_quality(&[]), 0.0);
+}
+
+#[test]
+pub fn test_max_homopolymer(){
+    assert_eq!(max_homopolymer(b"ACGTTTTAcg"), 4);
+    assert_eq!(max_homopolymer(b"AAaa"), 4);
+    assert_eq!(max_homopolymer(b""), 0);
+}
+
+#[test]
+pub fn test_quality_trim(){
+    // low quality ends of Q2 around a Q30 core
+    let mut qual = vec![b'#'; 5];
+    qual.extend(vec![b'?'; 20]);
+    qual.extend(vec![b'#'; 5]);
+    assert_eq!(quality_trim(&qual, "mott", 10, 4), (5, 25));
+    assert_eq!(quality_trim(&qual, "window", 10, 4), (3, 27));
+    assert_eq!(quality_trim(&[b'#'; 10], "mott", 10, 4), (0, 0));
+    assert_eq!(quality_trim(&qual, "none", 10, 4), (0, 30));
+}
+
+#[test]
+pub fn test_reverse_complement(){
+    assert_eq!(reverse_complement("ACGTn"), "nACGT");
+    assert_eq!(reverse_complement_bytes(b"AR-c"), b"gNNT");
+}
+
+#[test]
+pub fn test_edit_distance(){
+    assert_eq!(edit_distance(b"ACGTACGT", b"ACGTACGT"), 0);
+    assert_eq!(edit_distance(b"ACGTACGT", b"ACGAACGT"), 1);
+    assert_eq!(edit_distance(b"ACGTACGT", b"CGTACGTT"), 2);
+    assert_eq!(edit_distance(b"ACNTACGT", b"ACGTACG"), 1);
+}
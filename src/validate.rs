@@ -0,0 +1,126 @@
+use crate::error::{JasperError, Result};
+use crate::pattern::PatternArgs;
+use crate::utils::{edit_distance, reverse_complement};
+use log::{error, info, warn};
+
+/// Two patterns of one level closer than the errors the search allows.
+#[derive(Debug)]
+pub struct Collision {
+    pub level: usize,
+    pub pattern1: String,
+    pub pattern2: String,
+    pub strand: &'static str, // "+" compares both as given, "-" against the reverse complement
+    pub distance: usize,
+    pub max_dist: usize,      // errors allowed for the longer pattern of the pair
+    pub severity: &'static str, // "error": a perfect copy of one is accepted as the other, "warning": reads with errors are ambiguous
+}
+impl Collision {
+    pub fn to_tsv(&self) -> String {
+        format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            self.level, self.pattern1, self.pattern2, self.strand, self.distance, self.max_dist, self.severity
+        )
+    }
+}
+
+/// Pairwise edit distance of all patterns within each level, including reverse complements.
+/// The allowed errors of a pattern are its max_dist of the list or floor(len * errate) as in the
+/// search, with the errate of the list before the level errate; a pair within the
+/// allowed errors is an error, a pair within the errors of both patterns together or within the
+/// level's maxdist is a warning.
+pub fn check_collisions(patternargs: &PatternArgs) -> Vec<Collision> {
+    let mut collisions = Vec::new();
+    for (i, patternarg) in patternargs.pattern_vec.iter().enumerate() {
+        let patterndb = &patternarg.pattern_db;
+        let allowed = |name: &str, seq: &str| {
+            if let Some(max_dist) = patterndb.pattern_max_dist.get(name) {
+                return *max_dist as usize;
+            }
+            let errate = patterndb.pattern_errate.get(name).unwrap_or(&patternarg.pattern_errate);
+            (seq.trim_matches('N').len() as f32 * errate.0.max(errate.1)).floor() as usize
+        };
+        let mut patterns: Vec<(&String, &String)> = patterndb.f_patterns.iter().collect();
+        patterns.sort();
+        for (j, (name1, seq1)) in patterns.iter().enumerate() {
+            for (name2, seq2) in patterns.iter().skip(j + 1) {
+                let seq2_rc = reverse_complement(seq2);
+                for (strand, other) in [("+", seq2.as_str()), ("-", seq2_rc.as_str())] {
+                    let distance = edit_distance(seq1.as_bytes(), other.as_bytes());
+                    let (dist1, dist2) = (allowed(name1, seq1), allowed(name2, seq2));
+                    let severity = if distance <= dist1.max(dist2) {
+                        "error"
+                    } else if distance <= dist1 + dist2 || distance <= patternarg.pattern_maxdist {
+                        "warning"
+                    } else {
+                        continue;
+                    };
+                    collisions.push(Collision {
+                        level: i + 1,
+                        pattern1: name1.to_string(),
+                        pattern2: name2.to_string(),
+                        strand,
+                        distance,
+                        max_dist: dist1.max(dist2),
+                        severity,
+                    });
+                }
+            }
+        }
+    }
+    collisions
+}
+
+// collisions logged one by one at the start of a run, `jasper validate` lists all of them
+const MAX_LOGGED: usize = 10;
+
+/// Log the collisions and fail on the ones that can not be told apart. `jasper validate` is strict
+/// and fails on every error. A run only fails on identical patterns, which no search can tell apart;
+/// the best hit of the search still separates the other pairs, so they are warnings.
+pub fn log_collisions(collisions: &[Collision], strict: bool) -> Result<()> {
+    let fatal = |collision: &Collision| if strict { collision.severity == "error" } else { collision.distance == 0 };
+    for (i, collision) in collisions.iter().enumerate() {
+        if !strict && i == MAX_LOGGED {
+            warn!("... {} more close pattern pairs, run `jasper validate` with the same options to list all", collisions.len() - MAX_LOGGED);
+            break;
+        }
+        let message = format!(
+            "level {} pattern {} and {}{} are {} edits apart, allowed errors {}",
+            collision.level,
+            collision.pattern1,
+            collision.pattern2,
+            if collision.strand == "-" { " (reverse complement)" } else { "" },
+            collision.distance,
+            collision.max_dist,
+        );
+        if fatal(collision) {
+            error!("{}", message);
+        } else {
+            warn!("{}", message);
+        }
+    }
+    let errors = collisions.iter().filter(|collision| collision.severity == "error").count();
+    info!("validate patterns: {} errors, {} warnings.", errors, collisions.len() - errors);
+    let failed = collisions.iter().filter(|collision| fatal(collision)).count();
+    if failed > 0 {
+        return Err(JasperError::Collision(failed));
+    }
+    Ok(())
+}
+
+#[test]
+pub fn test_log_collisions(){
+    let collision = |distance: usize, severity: &'static str| Collision {
+        level: 1,
+        pattern1: "B1".to_string(),
+        pattern2: "B2".to_string(),
+        strand: "+",
+        distance,
+        max_dist: 3,
+        severity,
+    };
+    // a run only stops on identical patterns, `jasper validate` on every error
+    let close = [collision(2, "error"), collision(5, "warning")];
+    assert!(log_collisions(&close, false).is_ok());
+    assert!(matches!(log_collisions(&close, true), Err(JasperError::Collision(1))));
+    assert!(log_collisions(&[collision(0, "error")], false).is_err());
+}
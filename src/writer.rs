@@ -8,6 +8,7 @@ use std::io::Result;
 use std::path::Path;
 // use bio::io::fastq::Writer;
 use std::fs::create_dir_all;
+use crate::bam::BamWriter;
 use crate::fastq::ReadInfo;
 use std::io::BufWriter;
 use std::thread;
@@ -15,22 +16,27 @@ use flume::{Receiver, Sender, unbounded};
 pub struct WriterManager {
     writers: HashMap<String, Sender<ReadInfo>>,
     outdir: String,
+    stdout: Option<String>,
     pub logger: Vec<String>,
     handles: Vec<thread::JoinHandle<()>>,
 }
 
 impl WriterManager {
-    pub fn new(outdir: String) -> WriterManager {
+    pub fn new(outdir: String, stdout: Option<String>) -> WriterManager {
         info!("Creating writer manager, start writing...");
         WriterManager {
             writers: HashMap::new(),
             outdir,
+            stdout,
             logger: Vec::new(),
             handles: Vec::new(),
         }
     }
 
     pub fn write(&mut self, readinfo: ReadInfo) -> Result<()> {
+        if let Some(format) = self.stdout.clone() {
+            return self.write_stdout(readinfo, &format);
+        }
         if !readinfo.write_to_fq {
             return Ok(());
         }
@@ -39,7 +45,7 @@ impl WriterManager {
             let (tx, rx) = unbounded();
             let filepath = Path::new(&self.outdir).join(format!("{}.fq.gz", outfile));
             let filedir = filepath.parent().unwrap();
-            create_dir_all(&filedir).expect("fail to create output directory");
+            create_dir_all(filedir).expect("fail to create output directory");
             let file = File::create(&filepath).expect("fail to create output fq.gz");
             let encoder = GzEncoder::new(file, Compression::default());
             let writer = BufWriter::with_capacity(1_000_000, encoder);
@@ -50,22 +56,53 @@ impl WriterManager {
         Ok(())
     }
 
-    fn start_writing_thread(&mut self, mut writer: BufWriter<GzEncoder<File>>, rx: Receiver<ReadInfo>) {
+    fn write_stdout(&mut self, readinfo: ReadInfo, format: &str) -> Result<()> {
+        // every read goes to one stream, write_to_fq only matters for per-sample files
+        if !self.writers.contains_key("-") {
+            let (tx, rx) = unbounded();
+            let writer = BufWriter::with_capacity(1_000_000, std::io::stdout());
+            if format == "ubam" {
+                self.start_bam_thread(BamWriter::new(writer)?, rx);
+            } else {
+                self.start_writing_thread(writer, rx);
+            }
+            self.writers.insert("-".to_string(), tx);
+        }
+        self.writers.get("-").unwrap().send(readinfo).expect("readinfo to writer send fail");
+        Ok(())
+    }
+
+    fn start_writing_thread<W: Write + Send + 'static>(&mut self, mut writer: W, rx: Receiver<ReadInfo>) {
         let handle = thread::spawn(move || {
             for readinfo in rx.iter() {
                 let id =  readinfo.out_record.id();
                 let seq = std::str::from_utf8(readinfo.out_record.seq()).expect("Not a valid UTF-8 sequence");
                 let qual = std::str::from_utf8(readinfo.out_record.qual()).expect("Not a valid UTF-8 sequence");
-                let record_str = format!("@{}\n{}\n+\n{}\n", id, seq, qual);
+                let record_str = match readinfo.out_record.desc() {
+                    Some(desc) => format!("@{} {}\n{}\n+\n{}\n", id, desc, seq, qual),
+                    None => format!("@{}\n{}\n+\n{}\n", id, seq, qual),
+                };
                 write!(writer, "{}", record_str).unwrap();
             }
+            writer.flush().expect("fail to flush output");
+        });
+        self.handles.push(handle);
+    }
+
+    fn start_bam_thread<W: Write + Send + 'static>(&mut self, mut writer: BamWriter<W>, rx: Receiver<ReadInfo>) {
+        let handle = thread::spawn(move || {
+            for readinfo in rx.iter() {
+                let record = &readinfo.out_record;
+                writer.write_record(record.id(), record.seq(), record.qual(), &readinfo.out_tags).expect("fail to write ubam record");
+            }
+            writer.finish().expect("fail to finish ubam output");
         });
         self.handles.push(handle);
     }
 
     pub fn write_log_file(&self, outdir: &String) -> Result<()> {
         let dir_path = Path::new(outdir);
-        create_dir_all(&dir_path)?;
+        create_dir_all(dir_path)?;
         info!("Writing logger to reads_log.gz");
         let file_path = dir_path.join("reads_log.gz");
         let file = File::create(file_path)?;